@@ -1,5 +1,5 @@
 extern crate jsonrpc;
-extern crate rustc_serialize;
+extern crate serde_json;
 extern crate bufstream;
 extern crate chrono;
 extern crate rand;
@@ -9,31 +9,29 @@ extern crate fern;
 
 use std::net::TcpStream;
 
-use rustc_serialize::json::Json;
+use serde_json::Value;
 
 use bufstream::BufStream;
 
-use chrono::{UTC, Local};
+use chrono::{Utc, Local};
 
-use jsonrpc::proto::Request;
-use jsonrpc::proto::spec20::ClientStream;
+use jsonrpc::proto::{Id, Request};
+use jsonrpc::proto::spec::ClientStream;
 use jsonrpc::proto::trans::{SendRequest, GetResponse};
 
-fn generate_id() -> u64 {
-    UTC::now().timestamp() as u64 + rand::random::<u64>()
+fn generate_id() -> Id {
+    Id::Number(Utc::now().timestamp() + rand::random::<u32>() as i64)
 }
 
 fn main() {
-    let logger_config = fern::DispatchConfig {
-        format: Box::new(move|msg: &str, level: &log::LogLevel, location: &log::LogLocation| {
-            format!("[{}][{}] [{}] {}", Local::now().format("%Y-%m-%d][%H:%M:%S"),
-                    level, location.__module_path, msg)
-        }),
-        output: vec![fern::OutputConfig::stderr()],
-        level: log::LogLevelFilter::Trace
-    };
-
-    fern::init_global_logger(logger_config, log::LogLevelFilter::Debug).unwrap();
+    fern::Dispatch::new()
+        .format(|out, msg, record| {
+            out.finish(format_args!("[{}][{}] [{}] {}", Local::now().format("%Y-%m-%d][%H:%M:%S"),
+                                    record.level(), record.target(), msg))
+        })
+        .level(log::LevelFilter::Debug)
+        .chain(std::io::stderr())
+        .apply().unwrap();
 
     let mut stream = BufStream::new(TcpStream::connect("127.0.0.1:8007").unwrap());
     let mut client = ClientStream::new(&mut stream);
@@ -41,10 +39,10 @@ fn main() {
     {
 
         let request = Request::new("echo".to_owned(),
-                                   Some(Json::Array(vec![
-                                        Json::String("ping".to_owned()),
+                                   Some(Value::Array(vec![
+                                        Value::String("ping".to_owned()),
                                    ])),
-                                   Some(Json::U64(generate_id())));
+                                   Some(generate_id()));
 
         debug!("Request: {:?}", request);
 
@@ -57,11 +55,11 @@ fn main() {
 
     {
         let request = Request::new("add".to_owned(),
-                                   Some(Json::Array(vec![
-                                            Json::U64(1),
-                                            Json::U64(2),
+                                   Some(Value::Array(vec![
+                                            Value::from(1),
+                                            Value::from(2),
                                         ])),
-                                   Some(Json::U64(generate_id())));
+                                   Some(generate_id()));
         debug!("Request: {:?}", request);
 
         client.request(request).unwrap();
@@ -74,10 +72,10 @@ fn main() {
     {
         let requests = (0..3).map(|_| {
             Request::new("echo".to_owned(),
-                         Some(Json::Array(vec![
-                            Json::String("ping".to_owned()),
+                         Some(Value::Array(vec![
+                            Value::String("ping".to_owned()),
                          ])),
-                         Some(Json::U64(generate_id())))
+                         Some(generate_id()))
         }).collect::<Vec<Request>>();
         debug!("Request: {:?}", requests);
 
@@ -90,9 +88,9 @@ fn main() {
 
     {
         let request = Request::new_notify("notify".to_owned(),
-                                          Some(Json::Array(vec![
-                                                    Json::U64(1),
-                                                    Json::U64(2),
+                                          Some(Value::Array(vec![
+                                                    Value::from(1),
+                                                    Value::from(2),
                                                ])));
         debug!("Request: {:?}", request);
         client.request(request).unwrap();
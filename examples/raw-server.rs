@@ -1,5 +1,5 @@
 extern crate jsonrpc;
-extern crate rustc_serialize;
+extern crate serde_json;
 extern crate bufstream;
 #[macro_use]
 extern crate log;
@@ -9,43 +9,29 @@ extern crate chrono;
 use std::net::TcpListener;
 use std::thread;
 
-use rustc_serialize::json::Json;
+use serde_json::Value;
 
 use bufstream::BufStream;
 
 use chrono::Local;
 
-use jsonrpc::proto::{Request, Response};
+use jsonrpc::proto::{Id, Request, Response};
 use jsonrpc::proto::trans::{GetRequest, SendResponse, ClientRequest};
-use jsonrpc::proto::spec20::{errors, ServerStream};
+use jsonrpc::proto::spec::{errors, ServerStream};
 
 fn echo(req: Request) -> Response {
-    Response::result(req.params, req.id)
+    Response::result(Value::from(req.params), req.id)
 }
 
 fn add(req: Request) -> Response {
-    let params = match req.params {
-        Some(Json::Array(ref p)) if p.len() == 2 => p,
-        _ => {
-            return Response::error(errors::InvalidParams::new(), req.id);
+    let (a, b): (i64, i64) = match req.parse_params() {
+        Ok(params) => params,
+        Err(err) => {
+            return Response::error(err, req.id);
         }
     };
 
-    let a = match params[0].as_i64() {
-        Some(x) => x,
-        None => {
-            return Response::error(errors::InvalidParams::new(), req.id);
-        }
-    };
-
-    let b = match params[1].as_i64() {
-        Some(x) => x,
-        None => {
-            return Response::error(errors::InvalidParams::new(), req.id);
-        }
-    };
-
-    Response::result(Json::I64(a + b), req.id)
+    Response::result(Value::from(a + b), req.id)
 }
 
 fn dispatcher(req: Request) -> Option<Response> {
@@ -59,16 +45,14 @@ fn dispatcher(req: Request) -> Option<Response> {
 }
 
 fn main() {
-    let logger_config = fern::DispatchConfig {
-        format: Box::new(move|msg: &str, level: &log::LogLevel, location: &log::LogLocation| {
-            format!("[{}][{}] [{}] {}", Local::now().format("%Y-%m-%d][%H:%M:%S"),
-                    level, location.__module_path, msg)
-        }),
-        output: vec![fern::OutputConfig::stderr()],
-        level: log::LogLevelFilter::Trace
-    };
-
-    fern::init_global_logger(logger_config, log::LogLevelFilter::Debug).unwrap();
+    fern::Dispatch::new()
+        .format(|out, msg, record| {
+            out.finish(format_args!("[{}][{}] [{}] {}", Local::now().format("%Y-%m-%d][%H:%M:%S"),
+                                    record.level(), record.target(), msg))
+        })
+        .level(log::LevelFilter::Debug)
+        .chain(std::io::stderr())
+        .apply().unwrap();
 
     let acceptor = TcpListener::bind("127.0.0.1:8007").unwrap();
 
@@ -98,8 +82,12 @@ fn main() {
 
                         },
                         Ok(Some(ClientRequest::Batch(reqs))) => {
-                            let resps = reqs.into_iter().map(|r| dispatcher(r)).
-                                collect::<Vec<Option<Response>>>();
+                            let resps = reqs.into_iter().map(|r| {
+                                match r {
+                                    Ok(req) => dispatcher(req),
+                                    Err(err) => Some(Response::error(err, Id::Null)),
+                                }
+                            }).collect::<Vec<Option<Response>>>();
 
                             let mut final_response = vec![];
                             for res in resps {
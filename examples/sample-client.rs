@@ -0,0 +1,37 @@
+extern crate jsonrpc;
+#[macro_use]
+extern crate log;
+extern crate bufstream;
+extern crate fern;
+extern crate chrono;
+
+use std::net::TcpStream;
+
+use chrono::Local;
+
+use bufstream::BufStream;
+
+use jsonrpc::client::Client;
+use jsonrpc::proto::spec::ClientStream;
+
+fn main() {
+    fern::Dispatch::new()
+        .format(|out, msg, record| {
+            out.finish(format_args!("[{}][{}] [{}] {}", Local::now().format("%Y-%m-%d][%H:%M:%S"),
+                                    record.level(), record.target(), msg))
+        })
+        .level(log::LevelFilter::Debug)
+        .chain(std::io::stderr())
+        .apply().unwrap();
+
+    let mut stream = BufStream::new(TcpStream::connect("127.0.0.1:8080").unwrap());
+    let mut client = Client::new(ClientStream::new(&mut stream));
+
+    let echo: String = client.call("echo", ("ping",)).unwrap();
+    debug!("echo: {:?}", echo);
+
+    client.notify("touch", ("ping",)).unwrap();
+
+    let unknown = client.call::<_, String>("unknown", ());
+    debug!("unknown: {:?}", unknown);
+}
@@ -0,0 +1,158 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use tokio::sync::{self, oneshot};
+use tokio::time;
+
+use crate::RpcResult;
+use crate::client::{connection_closed, to_params};
+use crate::client::pending::{self, Pending};
+use crate::proto::{self, Id, Request, Response};
+
+use super::{AsyncGetResponse, AsyncSendRequest};
+
+type Waiter = oneshot::Sender<proto::Result<Response>>;
+
+struct Shared<W> {
+    writer: sync::Mutex<W>,
+    // Shared with the reader task, which must not keep the writer alive
+    pending: Arc<Mutex<Pending<Waiter>>>,
+    next_id: AtomicI64,
+}
+
+/// Client sharing one connection among many tasks, see `client::MultiplexClient`.
+///
+/// A task spawned on the current tokio runtime reads every response and hands it to the caller
+/// waiting for its `Id`.
+pub struct AsyncClient<W> {
+    shared: Arc<Shared<W>>,
+}
+
+impl<W> Clone for AsyncClient<W> {
+    fn clone(&self) -> AsyncClient<W> {
+        AsyncClient {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<W: AsyncSendRequest + 'static> AsyncClient<W> {
+    /// Spawns the response reader, so it must be called from within a tokio runtime.
+    pub fn new<R: AsyncGetResponse + 'static>(reader: R, writer: W) -> AsyncClient<W> {
+        let shared = Arc::new(Shared {
+            writer: sync::Mutex::new(writer),
+            pending: Arc::new(Mutex::new(Pending::new())),
+            next_id: AtomicI64::new(1),
+        });
+
+        let pending = shared.pending.clone();
+        tokio::spawn(async move { read_responses(reader, pending).await });
+
+        AsyncClient {
+            shared,
+        }
+    }
+
+    /// Calls `method` and deserializes its result, see `Client::call`.
+    pub async fn call<P, R>(&self, method: &str, params: P) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        let result = self.request(method, params).await?.into_result()?;
+        serde_json::from_value(result).map_err(|err| proto::Error::ParserError(err).into())
+    }
+
+    /// Same as `call`, but fails with `Error::Timeout` if no response arrives within `timeout`.
+    ///
+    /// A response arriving after that is dropped.
+    pub async fn call_timeout<P, R>(&self, method: &str, params: P, timeout: Duration) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        let result = self.request_timeout(method, params, Some(timeout)).await?.into_result()?;
+        serde_json::from_value(result).map_err(|err| proto::Error::ParserError(err).into())
+    }
+
+    pub async fn notify<P: Serialize>(&self, method: &str, params: P) -> RpcResult<()> {
+        let req = Request::new_notify(method.to_owned(), to_params(params)?);
+        self.shared.writer.lock().await.request(req).await.map_err(From::from)
+    }
+
+    /// Calls `method` and returns the raw response.
+    pub async fn request<P: Serialize>(&self, method: &str, params: P) -> RpcResult<Response> {
+        self.request_timeout(method, params, None).await
+    }
+
+    async fn request_timeout<P: Serialize>(&self, method: &str, params: P, timeout: Option<Duration>)
+        -> RpcResult<Response>
+    {
+        let id = Id::Number(self.shared.next_id.fetch_add(1, Ordering::Relaxed));
+        let req = Request::new(method.to_owned(), to_params(params)?, Some(id.clone()));
+
+        let (tx, rx) = oneshot::channel();
+        self.shared.pending.lock().unwrap().insert(id.clone(), tx)?;
+
+        if let Err(err) = self.shared.writer.lock().await.request(req).await {
+            self.shared.pending.lock().unwrap().remove(&id);
+            return Err(err.into());
+        }
+
+        let received = match timeout {
+            Some(timeout) => match time::timeout(timeout, rx).await {
+                Ok(received) => received,
+                Err(..) => {
+                    // Once out of the table, a late response is dropped by the reader
+                    self.shared.pending.lock().unwrap().remove(&id);
+                    return Err(proto::Error::Timeout(id).into());
+                },
+            },
+            None => rx.await,
+        };
+
+        match received {
+            Ok(resp) => resp.map_err(From::from),
+            Err(..) => Err(connection_closed("response channel closed").into()),
+        }
+    }
+
+    /// Number of calls still waiting for their response.
+    pub fn pending_calls(&self) -> usize {
+        self.shared.pending.lock().unwrap().len()
+    }
+}
+
+async fn read_responses<R: AsyncGetResponse>(mut reader: R, pending: Arc<Mutex<Pending<Waiter>>>) {
+    let reason = loop {
+        if let Some(reason) = pending::receive(&pending, reader.get_response().await) {
+            break reason;
+        }
+    };
+
+    pending.lock().unwrap().close(reason);
+}
@@ -0,0 +1,349 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use serde::Serialize;
+use serde_json::{self, Value};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::proto::{self, Request, Response};
+use crate::proto::framing::{lost_sync, DEFAULT_MAX_FRAME_SIZE};
+use crate::proto::spec::client::response_from_json;
+use crate::proto::spec::server::request_from_json;
+use crate::proto::trans::{ClientRequest, ServerResponse};
+
+use super::{AsyncGetRequest, AsyncGetResponse, AsyncSendRequest, AsyncSendResponse};
+
+/// Bytes read ahead of the message being decoded.
+///
+/// Messages are concatenated JSON values, exactly as `Concatenated` reads them on blocking streams.
+/// The bytes are scanned once for the end of the first value, and only then parsed.
+struct JsonBuffer {
+    buf: Vec<u8>,
+    max_size: usize,
+    // Line breaks left to drop after a syntax error, up to the end of its line
+    skip_lines: usize,
+    // How far the first value has been scanned, and the nesting found so far
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonBuffer {
+    fn new(max_size: usize) -> JsonBuffer {
+        JsonBuffer {
+            buf: Vec::new(),
+            max_size,
+            skip_lines: 0,
+            scanned: 0,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+
+    /// Reads the next JSON value from `reader`, returning `None` on a clean EOF.
+    ///
+    /// A value still incomplete past `max_size` bytes fails with a `LostSync` error, after which
+    /// the stream cannot be read any more.
+    async fn read_json<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> proto::Result<Option<Value>> {
+        loop {
+            while self.skip_lines > 0 {
+                match self.buf.iter().position(|&b| b == b'\n') {
+                    Some(end) => {
+                        self.buf.drain(..=end);
+                        self.skip_lines -= 1;
+                    },
+                    None => {
+                        self.buf.clear();
+                        break;
+                    }
+                }
+            }
+
+            if self.scanned == 0 {
+                let start = self.buf.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(self.buf.len());
+                self.buf.drain(..start);
+            }
+
+            if self.skip_lines == 0 {
+                if let Some(end) = self.scan() {
+                    return self.parse(end).map(Some);
+                }
+            }
+
+            if self.buf.len() > self.max_size {
+                self.buf.clear();
+                self.reset();
+                return Err(lost_sync(format!("Message exceeds the limit of {} bytes", self.max_size)));
+            }
+
+            if reader.read_buf(&mut self.buf).await? == 0 {
+                if self.buf.is_empty() || self.skip_lines > 0 {
+                    self.buf.clear();
+                    return Ok(None);
+                }
+                // Either a value that needs no delimiter, such as a number, or a truncated one
+                let end = self.buf.len();
+                let result = self.parse(end);
+                self.buf.clear();
+                return result.map(Some);
+            }
+        }
+    }
+
+    /// Scans on for the end of the first value, returning its offset once found.
+    fn scan(&mut self) -> Option<usize> {
+        while self.scanned < self.buf.len() {
+            let b = self.buf[self.scanned];
+            self.scanned += 1;
+
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        return Some(self.scanned);
+                    }
+                }
+                continue;
+            }
+
+            match b {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                // A stray closing bracket ends the value as well, for the parser to reject it
+                b'}' | b']' if self.depth <= 1 => return Some(self.scanned),
+                b'}' | b']' => self.depth -= 1,
+                // The end of a number or a literal
+                b if self.depth == 0 && self.scanned > 1 && (b.is_ascii_whitespace() || b == b',') => {
+                    return Some(self.scanned - 1);
+                },
+                _ => {},
+            }
+        }
+        None
+    }
+
+    /// Parses the value found in `buf[..end]`, dropping it from the buffer.
+    fn parse(&mut self, end: usize) -> proto::Result<Value> {
+        self.reset();
+
+        let mut stream = serde_json::Deserializer::from_slice(&self.buf[..end]).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(value)) => {
+                let end = stream.byte_offset();
+                self.buf.drain(..end);
+                Ok(value)
+            },
+            Some(Err(err)) => {
+                // Resume after the line of the error, which ends at the offending byte when its
+                // column is 0
+                self.skip_lines = if err.column() > 0 || err.is_eof() { err.line() } else { err.line() - 1 };
+                Err(proto::Error::ParserError(err))
+            },
+            None => unreachable!("a value was found in the buffer"),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.scanned = 0;
+        self.depth = 0;
+        self.in_string = false;
+        self.escaped = false;
+    }
+}
+
+async fn write_json<W, T>(writer: &mut W, message: &T) -> proto::Result<()>
+    where W: AsyncWrite + Unpin,
+          T: Serialize
+{
+    let mut buf = serde_json::to_vec(message).map_err(proto::Error::EncoderError)?;
+    buf.extend_from_slice(b"\r\n");
+
+    writer.write_all(&buf).await?;
+    writer.flush().await.map_err(From::from)
+}
+
+pub struct AsyncClientReader<R> {
+    reader: R,
+    buf: JsonBuffer,
+}
+
+pub struct AsyncClientWriter<W> {
+    writer: W,
+}
+
+pub struct AsyncServerReader<R> {
+    reader: R,
+    buf: JsonBuffer,
+}
+
+pub struct AsyncServerWriter<W> {
+    writer: W,
+}
+
+impl<R: AsyncRead + Unpin> AsyncClientReader<R> {
+    pub fn new(reader: R) -> AsyncClientReader<R> {
+        AsyncClientReader::with_max_frame_size(reader, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Messages longer than `max_frame_size` fail to read, and end the stream.
+    pub fn with_max_frame_size(reader: R, max_frame_size: usize) -> AsyncClientReader<R> {
+        AsyncClientReader {
+            reader,
+            buf: JsonBuffer::new(max_frame_size),
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.buf.max_size
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Returns the inner reader, dropping anything read ahead of the last response.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncClientWriter<W> {
+    pub fn new(writer: W) -> AsyncClientWriter<W> {
+        AsyncClientWriter {
+            writer,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncServerReader<R> {
+    pub fn new(reader: R) -> AsyncServerReader<R> {
+        AsyncServerReader::with_max_frame_size(reader, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Messages longer than `max_frame_size` fail to read, and end the stream.
+    pub fn with_max_frame_size(reader: R, max_frame_size: usize) -> AsyncServerReader<R> {
+        AsyncServerReader {
+            reader,
+            buf: JsonBuffer::new(max_frame_size),
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.buf.max_size
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Returns the inner reader, dropping anything read ahead of the last request.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncServerWriter<W> {
+    pub fn new(writer: W) -> AsyncServerWriter<W> {
+        AsyncServerWriter {
+            writer,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AsyncSendRequest for AsyncClientWriter<W> {
+    async fn request(&mut self, request: Request) -> proto::Result<()> {
+        write_json(&mut self.writer, &request).await
+    }
+
+    async fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        write_json(&mut self.writer, &requests).await
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> AsyncGetResponse for AsyncClientReader<R> {
+    async fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
+        match self.buf.read_json(&mut self.reader).await? {
+            Some(resp) => response_from_json(resp).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin + Send> AsyncSendResponse for AsyncServerWriter<W> {
+    async fn response(&mut self, response: Response) -> proto::Result<()> {
+        write_json(&mut self.writer, &response).await
+    }
+
+    async fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
+        if responses.is_empty() {
+            return Ok(());
+        }
+        write_json(&mut self.writer, &responses).await
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> AsyncGetRequest for AsyncServerReader<R> {
+    async fn get_request(&mut self) -> proto::Result<Option<ClientRequest>> {
+        match self.buf.read_json(&mut self.reader).await? {
+            Some(req) => request_from_json(req).map(Some),
+            None => Ok(None),
+        }
+    }
+}
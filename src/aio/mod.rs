@@ -0,0 +1,224 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Asynchronous counterparts of the transport traits, codecs, router and client, for use on tokio.
+//!
+//! Messages, error mapping and framing are the same as in the blocking API, so both sides can be
+//! mixed freely over the same connection.
+
+use std::future::Future;
+
+use crate::proto::{self, Request, Response};
+use crate::proto::trans::{ClientRequest, ServerResponse};
+
+pub use self::client::AsyncClient;
+pub use self::codec::{AsyncClientReader, AsyncClientWriter, AsyncServerReader, AsyncServerWriter};
+pub use self::router::{serve, AsyncDispatcher, AsyncHandler, AsyncRouter, BoxFuture};
+
+pub mod client;
+pub mod codec;
+pub mod router;
+
+pub trait AsyncSendRequest: Send {
+    fn request(&mut self, request: Request) -> impl Future<Output = proto::Result<()>> + Send;
+    fn batch_request(&mut self, requests: Vec<Request>) -> impl Future<Output = proto::Result<()>> + Send;
+}
+
+pub trait AsyncGetResponse: Send {
+    fn get_response(&mut self) -> impl Future<Output = proto::Result<Option<ServerResponse>>> + Send;
+}
+
+pub trait AsyncSendResponse: Send {
+    fn response(&mut self, response: Response) -> impl Future<Output = proto::Result<()>> + Send;
+    /// Writes nothing for an empty batch, as a batch of notifications must not be answered.
+    fn batch_response(&mut self, responses: Vec<Response>) -> impl Future<Output = proto::Result<()>> + Send;
+
+    fn server_response(&mut self, response: ServerResponse) -> impl Future<Output = proto::Result<()>> + Send {
+        async move {
+            match response {
+                ServerResponse::Single(resp) => self.response(resp).await,
+                ServerResponse::Batch(resps) => self.batch_response(resps).await,
+            }
+        }
+    }
+}
+
+pub trait AsyncGetRequest: Send {
+    fn get_request(&mut self) -> impl Future<Output = proto::Result<Option<ClientRequest>>> + Send;
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use serde_json::Value;
+    use tokio::io::{self, AsyncWriteExt};
+
+    use crate::{Error, RpcServerResult};
+    use crate::proto::{self, Id, Response};
+    use crate::proto::spec::errors;
+    use crate::proto::trans::{ClientRequest, ServerResponse};
+
+    use super::{serve, AsyncClient, AsyncClientReader, AsyncClientWriter, AsyncGetRequest,
+                AsyncGetResponse, AsyncRouter, AsyncServerReader, AsyncServerWriter};
+
+    #[tokio::test]
+    async fn test_async_codec() {
+        let (mut client, server) = io::duplex(1024);
+        let mut reader = AsyncServerReader::new(server);
+
+        // Messages split across writes, and concatenated without any separator
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"echo\",").await.unwrap();
+        client.write_all(b"\"params\":[\"ping\"],\"id\":1}{\"jsonrpc\":\"2.0\",\"method\":\"touch\"}\r\n").await.unwrap();
+        client.write_all(b"[]\r\n{\"jsonrpc\":\"2.0\",").await.unwrap();
+        drop(client);
+
+        match reader.get_request().await.unwrap() {
+            Some(ClientRequest::Single(req)) => {
+                assert_eq!("echo", req.method);
+                assert_eq!(Some(Id::Number(1)), req.id);
+            },
+            other => panic!("Expecting a request, but found {:?}", other),
+        }
+        match reader.get_request().await.unwrap() {
+            Some(ClientRequest::Single(req)) => assert_eq!(None, req.id),
+            other => panic!("Expecting a notification, but found {:?}", other),
+        }
+        match reader.get_request().await {
+            Err(proto::Error::InternalError(..)) => {},
+            other => panic!("Expecting an empty batch to be rejected, but found {:?}", other),
+        }
+        match reader.get_request().await {
+            Err(proto::Error::ParserError(ref err)) if err.is_eof() => {},
+            other => panic!("Expecting a truncated message, but found {:?}", other),
+        }
+        assert!(reader.get_request().await.unwrap().is_none());
+
+        // Reading resumes on the line after a syntax error, even if it was not read in one go
+        let (mut client, server) = io::duplex(1024);
+        let mut reader = AsyncServerReader::new(server);
+        client.write_all(b"{\"jsonrpc\" \"2.0\",").await.unwrap();
+        client.write_all(b"\"method\":\"echo\"}\r\n{\"jsonrpc\":\"2.0\",\"method\":\"touch\"}\r\n").await.unwrap();
+        drop(client);
+
+        match reader.get_request().await {
+            Err(proto::Error::ParserError(..)) => {},
+            other => panic!("Expecting a syntax error, but found {:?}", other),
+        }
+        match reader.get_request().await.unwrap() {
+            Some(ClientRequest::Single(req)) => assert_eq!("touch", req.method),
+            other => panic!("Expecting a notification, but found {:?}", other),
+        }
+        assert!(reader.get_request().await.unwrap().is_none());
+
+        // Brackets and escaped quotes within strings do not end a message early
+        let (mut client, server) = io::duplex(1024);
+        let mut reader = AsyncServerReader::with_max_frame_size(server, 128);
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"params\":[\"}\\\"").await.unwrap();
+        client.write_all(b"]\"]}\r\n{\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"params\":[\"").await.unwrap();
+        client.write_all(&[b'x'; 128]).await.unwrap();
+
+        match reader.get_request().await.unwrap() {
+            Some(ClientRequest::Single(req)) => assert_eq!(Some(Value::from(vec!["}\"]"])), req.params),
+            other => panic!("Expecting a request, but found {:?}", other),
+        }
+        match reader.get_request().await {
+            Err(err @ proto::Error::InternalError(..)) => assert!(!err.is_recoverable()),
+            other => panic!("Expecting the message to be refused, but found {:?}", other),
+        }
+
+        let (server, client) = io::duplex(64);
+        let mut reader = AsyncClientReader::new(client);
+        let mut writer = AsyncServerWriter::new(server);
+        super::AsyncSendResponse::response(&mut writer, Response::result(Value::from("pong"), 1)).await.unwrap();
+        match reader.get_response().await.unwrap() {
+            Some(ServerResponse::Single(resp)) => assert_eq!(Response::result(Value::from("pong"), 1), resp),
+            other => panic!("Expecting a response, but found {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_client_router() {
+        let touched = Arc::new(AtomicUsize::new(0));
+
+        let mut router = AsyncRouter::new();
+        router.add_method("add", |(a, b): (i64, i64)| async move { Ok(a + b) });
+        router.add_method("sleep", |ms: (u64,)| async move {
+            tokio::time::sleep(Duration::from_millis(ms.0)).await;
+            Ok(ms.0)
+        });
+        {
+            let touched = touched.clone();
+            router.add_method("touch", move |_: Value| {
+                touched.fetch_add(1, Ordering::SeqCst);
+                async { Ok(()) }
+            });
+        }
+        router.add_method("panic", |(now,): (bool,)| {
+            if now {
+                panic!("before the future");
+            }
+            async { panic!("within the future") as RpcServerResult<()> }
+        });
+
+        let (client_io, server_io) = io::duplex(1024);
+        let (server_read, server_write) = io::split(server_io);
+        let server = tokio::spawn(async move {
+            serve(&router, AsyncServerReader::new(server_read), AsyncServerWriter::new(server_write)).await
+        });
+
+        let (client_read, client_write) = io::split(client_io);
+        let client = AsyncClient::new(AsyncClientReader::new(client_read), AsyncClientWriter::new(client_write));
+
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).await.unwrap());
+        client.notify("touch", ()).await.unwrap();
+
+        match client.call::<_, i64>("sub", (1, 2)).await {
+            Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, err.code),
+            other => panic!("Expecting MethodNotFound, but found {:?}", other),
+        }
+        match client.call::<_, i64>("add", ("a",)).await {
+            Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_INVALID_PARAMS, err.code),
+            other => panic!("Expecting InvalidParams, but found {:?}", other),
+        }
+        for now in [true, false] {
+            match client.call::<_, ()>("panic", (now,)).await {
+                Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_INTERNAL_ERROR, err.code),
+                other => panic!("Expecting InternalError, but found {:?}", other),
+            }
+        }
+
+        // The server answers requests in turn, so the late response comes before the next one
+        match client.call_timeout::<_, u64>("sleep", (100,), Duration::from_millis(10)).await {
+            Err(Error::Timeout(..)) => {},
+            other => panic!("Expecting a timeout, but found {:?}", other),
+        }
+        assert_eq!(7, client.call::<_, i64>("add", (3, 4)).await.unwrap());
+        assert_eq!(0, client.pending_calls());
+        assert_eq!(1, touched.load(Ordering::SeqCst));
+
+        // Both halves of the connection are only closed once the response reader goes away too
+        server.abort();
+    }
+}
@@ -0,0 +1,205 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use std::collections::HashMap;
+use std::future::{self, Future};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use crate::RpcServerResult;
+use crate::proto::{self, Id, Request, Response};
+use crate::proto::spec::errors;
+use crate::router::{batch_response, panicked};
+use crate::proto::trans::{ClientRequest, ServerResponse};
+
+use super::{AsyncGetRequest, AsyncSendResponse};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Turns requests into responses, see `router::Dispatcher`.
+pub trait AsyncDispatcher: Sync {
+    /// Handles a single request, returning `None` if it is a notification.
+    fn dispatch(&self, req: Request) -> impl Future<Output = Option<Response>> + Send;
+
+    /// Handles a single request or a whole batch, returning `None` if there is nothing to reply.
+    fn dispatch_request(&self, req: ClientRequest) -> impl Future<Output = Option<ServerResponse>> + Send {
+        async move {
+            match req {
+                ClientRequest::Single(req) => self.dispatch(req).await.map(ServerResponse::Single),
+                ClientRequest::Batch(reqs) => {
+                    let mut resps = Vec::with_capacity(reqs.len());
+                    for r in reqs {
+                        let resp = match r {
+                            Ok(req) => self.dispatch(req).await,
+                            Err(err) => Some(Response::error(err, Id::Null)),
+                        };
+                        resps.extend(resp);
+                    }
+
+                    batch_response(resps)
+                }
+            }
+        }
+    }
+}
+
+/// Implementation of a method registered in an `AsyncRouter`.
+pub trait AsyncHandler: Send + Sync {
+    fn handle(&self, req: Request) -> BoxFuture<'static, RpcServerResult<Value>>;
+}
+
+struct MethodHandler<F, P, R> {
+    f: F,
+    _marker: PhantomData<fn(P) -> R>,
+}
+
+impl<P, R, F, Fut> AsyncHandler for MethodHandler<F, P, R>
+    where P: DeserializeOwned,
+          R: Serialize,
+          F: Fn(P) -> Fut + Send + Sync,
+          Fut: Future<Output = RpcServerResult<R>> + Send + 'static
+{
+    fn handle(&self, req: Request) -> BoxFuture<'static, RpcServerResult<Value>> {
+        let params = match req.parse_params() {
+            Ok(params) => params,
+            Err(err) => return Box::pin(future::ready(Err(err))),
+        };
+
+        let fut = (self.f)(params);
+        Box::pin(async move {
+            let result = fut.await?;
+            serde_json::to_value(result)
+                .map_err(|err| errors::InternalError::with_detail(err.to_string()))
+        })
+    }
+}
+
+/// Dispatches requests to the asynchronous handlers registered by method name.
+///
+/// Behaves like `router::Router`: unknown methods are answered with `MethodNotFound`, a handler
+/// that panics with `InternalError`, and the responses of notifications are dropped.
+#[derive(Default)]
+pub struct AsyncRouter {
+    methods: HashMap<String, Box<dyn AsyncHandler>>,
+}
+
+impl AsyncRouter {
+    pub fn new() -> AsyncRouter {
+        AsyncRouter {
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Registers a closure taking the deserialized params of the request and returning a future.
+    ///
+    /// Params that do not match `P` are answered with `InvalidParams`.
+    pub fn add_method<P, R, F, Fut>(&mut self, method: &str, f: F) -> &mut AsyncRouter
+        where P: DeserializeOwned + 'static,
+              R: Serialize + 'static,
+              F: Fn(P) -> Fut + Send + Sync + 'static,
+              Fut: Future<Output = RpcServerResult<R>> + Send + 'static
+    {
+        self.add_handler(method, Box::new(MethodHandler { f, _marker: PhantomData }))
+    }
+
+    pub fn add_handler(&mut self, method: &str, handler: Box<dyn AsyncHandler>) -> &mut AsyncRouter {
+        self.methods.insert(method.to_owned(), handler);
+        self
+    }
+
+    pub fn has_method(&self, method: &str) -> bool {
+        self.methods.contains_key(method)
+    }
+}
+
+impl AsyncDispatcher for AsyncRouter {
+    async fn dispatch(&self, req: Request) -> Option<Response> {
+        trace!("Dispatching request {:?}", req);
+
+        let id = req.id.clone();
+        let method = req.method.clone();
+        let result = match self.methods.get(&req.method) {
+            Some(handler) => {
+                let caught = match panic::catch_unwind(AssertUnwindSafe(|| handler.handle(req))) {
+                    Ok(fut) => CatchUnwind(fut).await,
+                    Err(payload) => Err(payload),
+                };
+                caught.unwrap_or_else(|payload| Err(panicked(&method, &*payload)))
+            },
+            None => {
+                Err(errors::MethodNotFound::with_detail(
+                        Value::String(format!("Unknown method {:?}", req.method))))
+            }
+        };
+
+        id.map(|id| Response::new(result, id))
+    }
+}
+
+/// Resolves to the panic of the future it wraps, instead of unwinding through its caller.
+struct CatchUnwind<F>(F);
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = thread::Result<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match panic::catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.0).poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+/// Answers the requests read from `reader` until EOF.
+pub async fn serve<D, R, W>(dispatcher: &D, mut reader: R, mut writer: W) -> proto::Result<()>
+    where D: AsyncDispatcher,
+          R: AsyncGetRequest,
+          W: AsyncSendResponse
+{
+    loop {
+        let req = match reader.get_request().await {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(err @ proto::Error::IoError(..)) => return Err(err),
+            Err(err) => {
+                warn!("Invalid request: {}", err);
+                writer.response(Response::error(err.to_protocol_error(), Id::Null)).await?;
+                if !err.is_recoverable() {
+                    return Err(err);
+                }
+                continue;
+            }
+        };
+
+        if let Some(resp) = dispatcher.dispatch_request(req).await {
+            writer.server_response(resp).await?;
+        }
+    }
+}
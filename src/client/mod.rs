@@ -0,0 +1,355 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use std::collections::HashSet;
+use std::io;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use crate::RpcResult;
+use crate::proto::{self, Id, Request, Response};
+use crate::proto::trans::{GetResponse, SendRequest, ServerResponse};
+
+pub use self::multiplex::MultiplexClient;
+
+pub mod multiplex;
+pub(crate) mod pending;
+
+/// Synchronous client over a `ClientStream` or any other `SendRequest + GetResponse`.
+///
+/// Every call gets a fresh `Id`, and waits for the response with that id. Other responses that
+/// arrive in the meantime are dropped: late ones of calls that timed out quietly, and those whose
+/// id matches no call with a warning.
+///
+/// Reading blocks inside the transport, so a deadline is only checked whenever the transport
+/// returns. Pair it with a read timeout on the underlying socket (`TcpStream::set_read_timeout`)
+/// so that a silent server cannot hold a call past its deadline. The transport must then resume
+/// a response cut by the timeout, as `ClientStream` and `ClientReader` do, or the stream is out
+/// of sync after it.
+pub struct Client<T> {
+    transport: T,
+    next_id: i64,
+    // Ids of calls that timed out, whose responses are dropped if they ever arrive
+    abandoned: HashSet<Id>,
+    timeout: Option<Duration>,
+}
+
+impl<T: SendRequest + GetResponse> Client<T> {
+    pub fn new(transport: T) -> Client<T> {
+        Client {
+            transport,
+            next_id: 0,
+            abandoned: HashSet::new(),
+            timeout: None,
+        }
+    }
+
+    /// Sets the deadline applied to every call on this connection, `None` to wait forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Calls `method` and deserializes its result.
+    ///
+    /// `params` is sent as is, so a tuple becomes positional params and a struct becomes named
+    /// ones. Params serializing to `null`, such as `()`, are omitted.
+    pub fn call<P, R>(&mut self, method: &str, params: P) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        let result = self.request(method, params)?.into_result()?;
+        serde_json::from_value(result).map_err(|err| proto::Error::ParserError(err).into())
+    }
+
+    /// Same as `call`, but fails with `Error::Timeout` if no response arrives within `timeout`.
+    pub fn call_timeout<P, R>(&mut self, method: &str, params: P, timeout: Duration) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        let result = self.request_deadline(method, params, Some(Instant::now() + timeout))?.into_result()?;
+        serde_json::from_value(result).map_err(|err| proto::Error::ParserError(err).into())
+    }
+
+    /// Sends a notification, which is never answered.
+    pub fn notify<P: Serialize>(&mut self, method: &str, params: P) -> RpcResult<()> {
+        let req = Request::new_notify(method.to_owned(), to_params(params)?);
+        self.transport.request(req).map_err(From::from)
+    }
+
+    /// Calls `method` and returns the raw response.
+    pub fn request<P: Serialize>(&mut self, method: &str, params: P) -> RpcResult<Response> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.request_deadline(method, params, deadline)
+    }
+
+    fn request_deadline<P: Serialize>(&mut self, method: &str, params: P, deadline: Option<Instant>)
+        -> RpcResult<Response>
+    {
+        self.next_id += 1;
+        let id = Id::Number(self.next_id);
+
+        let req = Request::new(method.to_owned(), to_params(params)?, Some(id.clone()));
+        self.transport.request(req)?;
+
+        let resp = self.wait_response(&id, deadline);
+        if let Err(proto::Error::Timeout(..)) = resp {
+            self.abandoned.insert(id);
+        }
+        resp.map_err(From::from)
+    }
+
+    fn wait_response(&mut self, id: &Id, deadline: Option<Instant>) -> proto::Result<Response> {
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(proto::Error::Timeout(id.clone()));
+            }
+
+            let resp = match self.transport.get_response() {
+                Ok(resp) => resp,
+                // The socket read timeout fired, go check the deadline. The transport keeps
+                // whatever part of the response it has read
+                Err(proto::Error::IoError(ref err)) if deadline.is_some()
+                    && (err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut) => {
+                    continue;
+                },
+                Err(err) => return Err(err),
+            };
+
+            let resps = match resp {
+                Some(ServerResponse::Single(resp)) => {
+                    if resp.id() == id {
+                        return Ok(resp);
+                    }
+                    // The server could not read our request well enough to find its id, and we
+                    // only have one request in flight
+                    if resp.id() == &Id::Null && !resp.is_success() {
+                        return Ok(resp);
+                    }
+                    vec![resp]
+                },
+                Some(ServerResponse::Batch(resps)) => resps,
+                None => {
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof,
+                                             format!("connection closed while waiting for response of {}", id));
+                    return Err(proto::Error::IoError(err));
+                }
+            };
+
+            let mut found = None;
+            for resp in resps {
+                if resp.id() == id {
+                    found = Some(resp);
+                } else {
+                    self.drop_response(resp);
+                }
+            }
+            if let Some(resp) = found {
+                return Ok(resp);
+            }
+        }
+    }
+
+    fn drop_response(&mut self, resp: Response) {
+        if self.abandoned.remove(resp.id()) {
+            debug!("Dropping late response of {}", resp.id());
+        } else {
+            warn!("Dropping response of {}, which matches no call", resp.id());
+        }
+    }
+}
+
+pub(crate) fn to_params<P: Serialize>(params: P) -> proto::Result<Option<Value>> {
+    match serde_json::to_value(params).map_err(proto::Error::EncoderError)? {
+        Value::Null => Ok(None),
+        params => Ok(Some(params)),
+    }
+}
+
+pub(crate) fn connection_closed(reason: &str) -> proto::Error {
+    proto::Error::IoError(io::Error::new(io::ErrorKind::ConnectionAborted, reason.to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read, Write};
+    use std::time::Duration;
+
+    use crate::Error;
+    use crate::proto::Id;
+    use crate::proto::framing::{Concatenated, ContentLength, Framing};
+    use crate::proto::spec::{errors, ClientStream};
+
+    use super::Client;
+
+    // Replays canned responses, whatever gets written
+    struct Scripted {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_client_responses() {
+        // Responses to ids of no call are dropped, alone or in a batch
+        let mut stream = Scripted {
+            input: Cursor::new(b"{\"id\":9,\"jsonrpc\":\"2.0\",\"result\":\"z\"}\r\n\
+                                 [{\"id\":8,\"jsonrpc\":\"2.0\",\"result\":\"y\"},\
+                                  {\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"a\"}]\r\n\
+                                 {\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"b\"}\r\n\
+                                 {\"id\":3,\"jsonrpc\":\"2.0\",\"error\":{\"code\":-32601,\"message\":\"Method not found\"}}\r\n\
+                                 {\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"b\"}\r\n".to_vec()),
+            output: Vec::new(),
+        };
+
+        {
+            let mut client = Client::new(ClientStream::new(&mut stream));
+            client.notify("touch", ("ping",)).unwrap();
+            assert_eq!("a", client.call::<_, String>("echo", ("a",)).unwrap());
+            assert_eq!("b", client.call::<_, String>("echo", ("b",)).unwrap());
+            match client.call::<_, String>("unknown", ()) {
+                Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, err.code),
+                other => panic!("Expecting MethodNotFound, but found {:?}", other),
+            }
+            match client.call::<_, String>("echo", ("c",)) {
+                Err(Error::IoError(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {},
+                other => panic!("Expecting EOF, but found {:?}", other),
+            }
+        }
+
+        let expected = "{\"jsonrpc\":\"2.0\",\"method\":\"touch\",\"params\":[\"ping\"]}\r\n\
+                        {\"id\":1,\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"params\":[\"a\"]}\r\n\
+                        {\"id\":2,\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"params\":[\"b\"]}\r\n\
+                        {\"id\":3,\"jsonrpc\":\"2.0\",\"method\":\"unknown\"}\r\n\
+                        {\"id\":4,\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"params\":[\"c\"]}\r\n";
+        assert_eq!(expected, String::from_utf8(stream.output).unwrap());
+    }
+
+    #[test]
+    fn test_client_timeout() {
+        let mut stream = Scripted {
+            input: Cursor::new(b"{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"late\"}\r\n\
+                                 {\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"b\"}\r\n".to_vec()),
+            output: Vec::new(),
+        };
+
+        let mut client = Client::new(ClientStream::new(&mut stream));
+        match client.call_timeout::<_, String>("echo", ("a",), Duration::from_secs(0)) {
+            Err(Error::Timeout(id)) => assert_eq!(Id::Number(1), id),
+            other => panic!("Expecting a timeout, but found {:?}", other),
+        }
+
+        // The late response of the first call must not be mistaken for this one
+        client.set_timeout(Some(Duration::from_secs(10)));
+        assert_eq!("b", client.call::<_, String>("echo", ("b",)).unwrap());
+        assert!(client.abandoned.is_empty());
+    }
+
+    // Times out before each chunk, as a socket with a read timeout on a slow server
+    struct Stuttering {
+        chunks: Vec<Vec<u8>>,
+        timed_out: bool,
+        output: Vec<u8>,
+    }
+
+    impl Read for Stuttering {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.chunks.is_empty() {
+                return Ok(0);
+            }
+            self.timed_out = !self.timed_out;
+            if self.timed_out {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "timed out"));
+            }
+
+            let chunk = &mut self.chunks[0];
+            let n = buf.len().min(chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                self.chunks.remove(0);
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Stuttering {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_client_read_timeout() {
+        fn call<F: Framing>(framing: F, response: &str) -> String {
+            let mut chunks = response.as_bytes().chunks(5).map(|chunk| chunk.to_vec()).collect::<Vec<_>>();
+            chunks.push(b"{\"id\":2,\"jsonrpc\":\"2.0\",\"result\":\"b\"}".to_vec());
+            let stream = Stuttering { chunks, timed_out: false, output: Vec::new() };
+
+            // A response cut by a timeout is resumed where it stopped
+            let mut client = Client::new(ClientStream::with_framing(stream, framing));
+            client.set_timeout(Some(Duration::from_secs(10)));
+            client.call::<_, String>("echo", ("a",)).unwrap()
+        }
+
+        let response = "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"a\"}";
+        assert_eq!("a", call(Concatenated, response));
+        assert_eq!("a", call(ContentLength::new(), &format!("Content-Length: {}\r\n\r\n{}", response.len(), response)));
+    }
+}
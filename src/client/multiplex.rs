@@ -0,0 +1,443 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use std::io::{self, BufReader, BufWriter};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use crate::RpcResult;
+use crate::proto::{self, Id, Request, Response};
+use crate::proto::spec::{ClientReader, ClientWriter};
+use crate::proto::trans::{GetResponse, SendRequest};
+
+use super::{connection_closed, to_params};
+use super::pending::{self, Pending};
+
+pub(crate) type Waiter = Sender<proto::Result<Response>>;
+
+/// Shuts a connection down, from any thread.
+pub(crate) type Closer = Box<dyn FnOnce() + Send + Sync>;
+
+struct Shared {
+    writer: Mutex<Box<dyn SendRequest + Send>>,
+    // Shared with the reader thread, which must not keep the writer alive
+    pending: Arc<Mutex<Pending<Waiter>>>,
+    next_id: AtomicI64,
+    timeout: Mutex<Option<Duration>>,
+    // Called once the last handle is dropped, to wake up a reader blocked on a shared socket
+    closer: Mutex<Option<Closer>>,
+}
+
+impl Shared {
+    fn shutdown(&self) {
+        if let Some(closer) = self.closer.lock().unwrap().take() {
+            closer();
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Client sharing one connection among many threads.
+///
+/// A background thread reads every response and hands it to the caller waiting for its `Id`.
+/// When the connection drops, every outstanding call fails with an I/O error, and so does any
+/// later one. A call past its deadline fails with `Error::Timeout`, and its response is dropped
+/// if it ever arrives.
+///
+/// An error response with a null id fails the only outstanding call, or every one of them if
+/// there are several, as it cannot be told which request it answers. A response that leaves the
+/// stream out of sync fails them all and closes the client.
+///
+/// Dropping every clone drops the writer. A client made by `from_tcp` also shuts the socket down,
+/// which stops the background thread, while any other reader is left until it reaches EOF.
+#[derive(Clone)]
+pub struct MultiplexClient {
+    shared: Arc<Shared>,
+}
+
+impl MultiplexClient {
+    pub fn new<R, W>(reader: R, writer: W) -> MultiplexClient
+        where R: GetResponse + Send + 'static,
+              W: SendRequest + Send + 'static
+    {
+        MultiplexClient::spawn(reader, writer, None)
+    }
+
+    /// Creates a client whose responses are read by someone else, and handed to `pending`.
+    pub(crate) fn with_closer<W>(writer: W, closer: Option<Closer>) -> MultiplexClient
+        where W: SendRequest + Send + 'static
+    {
+        MultiplexClient {
+            shared: Arc::new(Shared {
+                writer: Mutex::new(Box::new(writer)),
+                pending: Arc::new(Mutex::new(Pending::new())),
+                next_id: AtomicI64::new(1),
+                timeout: Mutex::new(None),
+                closer: Mutex::new(closer),
+            }),
+        }
+    }
+
+    fn spawn<R, W>(reader: R, writer: W, closer: Option<Closer>) -> MultiplexClient
+        where R: GetResponse + Send + 'static,
+              W: SendRequest + Send + 'static
+    {
+        let client = MultiplexClient::with_closer(writer, closer);
+
+        let pending = client.shared.pending.clone();
+        thread::spawn(move|| read_responses(reader, pending));
+
+        client
+    }
+
+    pub fn from_tcp(stream: TcpStream) -> io::Result<MultiplexClient> {
+        let reader = ClientReader::new(BufReader::new(stream.try_clone()?));
+        let closer = tcp_closer(&stream)?;
+        let writer = ClientWriter::new(BufWriter::new(stream));
+        Ok(MultiplexClient::spawn(reader, writer, Some(closer)))
+    }
+
+    /// Sets the deadline applied to every call on this connection, `None` to wait forever.
+    ///
+    /// It is shared by all clones of this client.
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        *self.shared.timeout.lock().unwrap() = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        *self.shared.timeout.lock().unwrap()
+    }
+
+    /// Calls `method` and deserializes its result, see `Client::call`.
+    pub fn call<P, R>(&self, method: &str, params: P) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        let result = self.request(method, params)?.into_result()?;
+        serde_json::from_value(result).map_err(|err| proto::Error::ParserError(err).into())
+    }
+
+    /// Same as `call`, but fails with `Error::Timeout` if no response arrives within `timeout`.
+    pub fn call_timeout<P, R>(&self, method: &str, params: P, timeout: Duration) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        let result = self.request_deadline(method, params, Some(Instant::now() + timeout))?.into_result()?;
+        serde_json::from_value(result).map_err(|err| proto::Error::ParserError(err).into())
+    }
+
+    pub fn notify<P: Serialize>(&self, method: &str, params: P) -> RpcResult<()> {
+        let req = Request::new_notify(method.to_owned(), to_params(params)?);
+        self.shared.writer.lock().unwrap().request(req).map_err(From::from)
+    }
+
+    /// Calls `method` and returns the raw response.
+    pub fn request<P: Serialize>(&self, method: &str, params: P) -> RpcResult<Response> {
+        let deadline = self.timeout().map(|timeout| Instant::now() + timeout);
+        self.request_deadline(method, params, deadline)
+    }
+
+    fn request_deadline<P: Serialize>(&self, method: &str, params: P, deadline: Option<Instant>)
+        -> RpcResult<Response>
+    {
+        let id = Id::Number(self.shared.next_id.fetch_add(1, Ordering::Relaxed));
+        let req = Request::new(method.to_owned(), to_params(params)?, Some(id.clone()));
+
+        let (tx, rx) = mpsc::channel();
+        self.shared.pending.lock().unwrap().insert(id.clone(), tx)?;
+
+        if let Err(err) = self.shared.writer.lock().unwrap().request(req) {
+            self.shared.pending.lock().unwrap().remove(&id);
+            return Err(err.into());
+        }
+
+        let received = match deadline {
+            Some(deadline) => rx.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok(resp) => resp.map_err(From::from),
+            Err(RecvTimeoutError::Timeout) => {
+                // Once out of the table, a late response is dropped by the reader, unless it
+                // raced us and is already in the channel
+                if self.shared.pending.lock().unwrap().remove(&id).is_none() {
+                    if let Ok(resp) = rx.try_recv() {
+                        return resp.map_err(From::from);
+                    }
+                }
+                Err(proto::Error::Timeout(id).into())
+            },
+            Err(RecvTimeoutError::Disconnected) => Err(connection_closed("response channel closed").into()),
+        }
+    }
+
+    /// Number of calls still waiting for their response.
+    pub fn pending_calls(&self) -> usize {
+        self.shared.pending.lock().unwrap().len()
+    }
+
+    /// The calls waiting for their response, for a reader of its own to hand them out.
+    pub(crate) fn pending(&self) -> Arc<Mutex<Pending<Waiter>>> {
+        self.shared.pending.clone()
+    }
+
+    /// Shuts the connection down now, rather than once the last handle is dropped.
+    pub(crate) fn shutdown(&self) {
+        self.shared.shutdown();
+    }
+}
+
+/// Shuts `stream` down in both directions, waking up whoever is blocked reading it.
+pub(crate) fn tcp_closer(stream: &TcpStream) -> io::Result<Closer> {
+    let handle = stream.try_clone()?;
+    Ok(Box::new(move|| {
+        if let Err(err) = handle.shutdown(Shutdown::Both) {
+            debug!("Failed to shut down connection: {}", err);
+        }
+    }))
+}
+
+fn read_responses<R: GetResponse>(mut reader: R, pending: Arc<Mutex<Pending<Waiter>>>) {
+    let reason = loop {
+        if let Some(reason) = pending::receive(&pending, reader.get_response()) {
+            break reason;
+        }
+    };
+
+    pending.lock().unwrap().close(reason);
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, BufReader, BufWriter, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use crate::Error;
+    use crate::proto::{Id, Response};
+    use crate::proto::framing::ContentLength;
+    use crate::proto::spec::{errors, ClientReader, ClientWriter, ServerReader, ServerWriter};
+    use crate::proto::trans::{ClientRequest, GetRequest, SendResponse};
+
+    use super::MultiplexClient;
+
+    #[test]
+    fn test_multiplex_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Collects 4 requests, answers them in reverse order and hangs up
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = ServerReader::new(BufReader::new(stream.try_clone().unwrap()));
+            let mut writer = ServerWriter::new(BufWriter::new(stream));
+
+            let mut reqs = Vec::new();
+            while reqs.len() < 4 {
+                match server.get_request().unwrap() {
+                    Some(ClientRequest::Single(req)) => reqs.push(req),
+                    other => panic!("Unexpected request {:?}", other),
+                }
+            }
+
+            for req in reqs.into_iter().rev() {
+                if req.method == "echo" {
+                    writer.response(Response::result(req.params.unwrap(), req.id.unwrap())).unwrap();
+                }
+            }
+        });
+
+        let client = MultiplexClient::from_tcp(TcpStream::connect(addr).unwrap()).unwrap();
+        let workers = (0..3).map(|i| {
+            let client = client.clone();
+            thread::spawn(move|| client.call::<_, Value>("echo", (i,)))
+        }).collect::<Vec<_>>();
+
+        // Never answered, so it fails when the server hangs up
+        let hanging = {
+            let client = client.clone();
+            thread::spawn(move|| client.call::<_, Value>("hang", ()))
+        };
+
+        for (i, worker) in workers.into_iter().enumerate() {
+            assert_eq!(Value::from(vec![i]), worker.join().unwrap().unwrap());
+        }
+
+        match hanging.join().unwrap() {
+            Err(Error::IoError(ref err)) if err.kind() == io::ErrorKind::ConnectionAborted => {},
+            other => panic!("Expecting the call to be aborted, but found {:?}", other),
+        }
+
+        server.join().unwrap();
+        assert_eq!(0, client.pending_calls());
+        assert!(client.call::<_, Value>("echo", ()).is_err());
+    }
+
+    #[test]
+    fn test_multiplex_client_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Only answers the first request once the second one arrives
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = ServerReader::new(BufReader::new(stream.try_clone().unwrap()));
+            let mut writer = ServerWriter::new(BufWriter::new(stream));
+
+            let mut reqs = Vec::new();
+            while reqs.len() < 2 {
+                match server.get_request().unwrap() {
+                    Some(ClientRequest::Single(req)) => reqs.push(req),
+                    other => panic!("Unexpected request {:?}", other),
+                }
+            }
+
+            for req in reqs {
+                writer.response(Response::result(Value::from(req.method), req.id.unwrap())).unwrap();
+            }
+        });
+
+        let client = MultiplexClient::from_tcp(TcpStream::connect(addr).unwrap()).unwrap();
+        match client.call_timeout::<_, Value>("slow", (), Duration::from_millis(50)) {
+            Err(Error::Timeout(id)) => assert_eq!(Id::Number(1), id),
+            other => panic!("Expecting a timeout, but found {:?}", other),
+        }
+        assert_eq!(0, client.pending_calls());
+
+        client.set_timeout(Some(Duration::from_secs(10)));
+        assert_eq!(Value::from("echo"), client.call::<_, Value>("echo", ()).unwrap());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_multiplex_client_null_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Answers the request as if it could not be read, then waits for the client to go away
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = ServerReader::new(BufReader::new(stream.try_clone().unwrap()));
+            let mut writer = ServerWriter::new(BufWriter::new(stream));
+
+            server.get_request().unwrap().unwrap();
+            writer.response(Response::error(errors::InvalidRequest::with_detail("unreadable".to_owned()), Id::Null)).unwrap();
+            server.get_request().unwrap()
+        });
+
+        let client = MultiplexClient::from_tcp(TcpStream::connect(addr).unwrap()).unwrap();
+        match client.call::<_, Value>("echo", ()) {
+            Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_INVALID_REQUEST, err.code),
+            other => panic!("Expecting the error, but found {:?}", other),
+        }
+        assert_eq!(0, client.pending_calls());
+
+        // Dropping the last handle closes the connection
+        drop(client);
+        assert!(server.join().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_multiplex_client_null_id_several() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Waits for both requests before answering with a single null id error
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = ServerReader::new(BufReader::new(stream.try_clone().unwrap()));
+            let mut writer = ServerWriter::new(BufWriter::new(stream));
+
+            server.get_request().unwrap().unwrap();
+            server.get_request().unwrap().unwrap();
+            writer.response(Response::error(errors::InvalidRequest::with_detail("unreadable".to_owned()), Id::Null)).unwrap();
+            server.get_request().unwrap()
+        });
+
+        let client = MultiplexClient::from_tcp(TcpStream::connect(addr).unwrap()).unwrap();
+        let workers = (0..2).map(|i| {
+            let client = client.clone();
+            thread::spawn(move|| client.call::<_, Value>("echo", (i,)))
+        }).collect::<Vec<_>>();
+
+        // Neither call can be told apart, so both fail rather than one waiting forever
+        for worker in workers {
+            match worker.join().unwrap() {
+                Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_INVALID_REQUEST, err.code),
+                other => panic!("Expecting the error, but found {:?}", other),
+            }
+        }
+
+        drop(client);
+        assert!(server.join().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_multiplex_client_lost_sync() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Answers with a frame whose length cannot be read, so nothing after it can be trusted
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut server = ServerReader::with_framing(BufReader::new(stream.try_clone().unwrap()), ContentLength::new());
+            let mut stream = stream;
+
+            server.get_request().unwrap().unwrap();
+            stream.write_all(b"Content-Length: many\r\n\r\n{}").unwrap();
+            server.get_request().unwrap()
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let reader = ClientReader::with_framing(BufReader::new(stream.try_clone().unwrap()), ContentLength::new());
+        let writer = ClientWriter::with_framing(BufWriter::new(stream.try_clone().unwrap()), ContentLength::new());
+        let client = MultiplexClient::new(reader, writer);
+
+        // The call fails as soon as the frame is read, and so does any later one
+        match client.call::<_, Value>("echo", ()) {
+            Err(Error::IoError(ref err)) if err.kind() == io::ErrorKind::ConnectionAborted => {},
+            other => panic!("Expecting the call to be aborted, but found {:?}", other),
+        }
+        assert!(client.call::<_, Value>("echo", ()).is_err());
+
+        stream.shutdown(Shutdown::Both).unwrap();
+        assert!(server.join().unwrap().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+
+//! Calls waiting for their response, shared by the clients reading responses in the background.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc;
+
+use crate::proto::{self, Id, Response};
+use crate::proto::trans::ServerResponse;
+
+use super::connection_closed;
+
+/// Where the response of one call is handed to the caller.
+pub(crate) trait Waiter {
+    fn send(self, resp: proto::Result<Response>);
+}
+
+impl Waiter for mpsc::Sender<proto::Result<Response>> {
+    fn send(self, resp: proto::Result<Response>) {
+        // The caller may have given up already
+        let _ = mpsc::Sender::send(&self, resp);
+    }
+}
+
+#[cfg(feature = "async")]
+impl Waiter for tokio::sync::oneshot::Sender<proto::Result<Response>> {
+    fn send(self, resp: proto::Result<Response>) {
+        let _ = tokio::sync::oneshot::Sender::send(self, resp);
+    }
+}
+
+/// Table of the calls waiting for their response, by `Id`.
+pub(crate) struct Pending<T> {
+    waiters: HashMap<Id, T>,
+    // Set once the reader has stopped, so that no new call waits forever
+    closed: Option<String>,
+}
+
+impl<T: Waiter> Pending<T> {
+    pub(crate) fn new() -> Pending<T> {
+        Pending {
+            waiters: HashMap::new(),
+            closed: None,
+        }
+    }
+
+    /// Registers the call `id`, failing if responses cannot be read any more.
+    pub(crate) fn insert(&mut self, id: Id, waiter: T) -> proto::Result<()> {
+        if let Some(ref reason) = self.closed {
+            return Err(connection_closed(reason));
+        }
+        self.waiters.insert(id, waiter);
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, id: &Id) -> Option<T> {
+        self.waiters.remove(id)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// Hands `resp` to the call waiting for it.
+    ///
+    /// An error with a null id answers a request the server could not read well enough to find
+    /// its id. It fails the one outstanding call if there is only one. With several, nothing tells
+    /// which one it was, and that one would wait forever, so as a last resort they all fail.
+    pub(crate) fn deliver(&mut self, resp: Response) {
+        if resp.id() == &Id::Null && !resp.is_success() {
+            match self.waiters.len() {
+                0 => warn!("Dropping error response with a null id: {:?}", resp),
+                1 => {},
+                n => warn!("Failing all {} outstanding calls with an error response with a null id: {:?}", n, resp),
+            }
+            for (_, waiter) in self.waiters.drain() {
+                waiter.send(Ok(resp.clone()));
+            }
+            return;
+        }
+
+        match self.waiters.remove(resp.id()) {
+            Some(waiter) => waiter.send(Ok(resp)),
+            None => warn!("Dropping response of unknown id {}: {:?}", resp.id(), resp),
+        }
+    }
+
+    /// Fails every outstanding call and any later one, once responses cannot be read any more.
+    pub(crate) fn close(&mut self, reason: String) {
+        debug!("Response reader stopped: {}", reason);
+
+        for (_, waiter) in self.waiters.drain() {
+            waiter.send(Err(connection_closed(&reason)));
+        }
+        self.closed = Some(reason);
+    }
+}
+
+/// Hands out what a background reader read, returning why it must stop if it must.
+pub(crate) fn receive<T: Waiter>(pending: &Mutex<Pending<T>>,
+                                 read: proto::Result<Option<ServerResponse>>)
+                                 -> Option<String> {
+    match read {
+        Ok(Some(ServerResponse::Single(resp))) => pending.lock().unwrap().deliver(resp),
+        Ok(Some(ServerResponse::Batch(resps))) => {
+            let mut pending = pending.lock().unwrap();
+            for resp in resps {
+                pending.deliver(resp);
+            }
+        },
+        Ok(None) => return Some("connection closed by peer".to_owned()),
+        // The stream cannot be trusted to be at a message boundary any more
+        Err(err @ proto::Error::IoError(..)) | Err(err @ proto::Error::ParserError(..)) => {
+            return Some(err.to_string());
+        },
+        Err(err) if !err.is_recoverable() => return Some(err.to_string()),
+        Err(err) => warn!("Dropping invalid response: {}", err),
+    }
+    None
+}
@@ -1,12 +1,14 @@
-
 use std::io;
 use std::convert::From;
 
-use proto::ProtocolError;
+use crate::proto::{self, Id, ProtocolError};
 
+#[derive(Debug)]
 pub enum Error {
     ProtocolError(ProtocolError),
     IoError(io::Error),
+    /// The call got no response before its deadline
+    Timeout(Id),
 }
 
 impl From<io::Error> for Error {
@@ -20,3 +22,13 @@ impl From<ProtocolError> for Error {
         Error::ProtocolError(e)
     }
 }
+
+impl From<proto::Error> for Error {
+    fn from(e: proto::Error) -> Error {
+        match e {
+            proto::Error::IoError(err) => Error::IoError(err),
+            proto::Error::Timeout(id) => Error::Timeout(id),
+            e => Error::ProtocolError(e.to_protocol_error()),
+        }
+    }
+}
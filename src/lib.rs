@@ -1,16 +1,26 @@
-#![feature(io)]
-extern crate rustc_serialize;
+extern crate serde;
+extern crate serde_json;
 #[macro_use]
 extern crate log;
 
-use rustc_serialize::json::ToJson;
+pub use crate::error::Error;
 
-pub use error::Error;
+#[macro_use]
+#[doc(hidden)]
+pub mod macros;
 
 pub mod error;
 pub mod proto;
-// pub mod client;
+pub mod router;
+pub mod client;
+pub mod server;
+pub mod runtime;
+pub mod peer;
+pub mod pubsub;
+pub mod transport;
+#[cfg(feature = "async")]
+pub mod aio;
 
-pub type RpcResult<T: ToJson> = Result<T, Error>;
+pub type RpcResult<T> = Result<T, Error>;
 
-pub type RpcServerResult<T: ToJson> = Result<T, proto::ProtocolError>;
+pub type RpcServerResult<T> = Result<T, proto::ProtocolError>;
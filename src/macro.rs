@@ -1,4 +0,0 @@
-
-macro_rules! json_rpc {
-    () => ()
-}
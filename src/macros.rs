@@ -0,0 +1,298 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Support for the `json_rpc!` macro, not meant to be used directly.
+
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use crate::RpcServerResult;
+use crate::proto::ProtocolError;
+use crate::proto::spec::errors;
+use crate::router::Params;
+
+/// Defines a service from a list of methods.
+///
+/// ```
+/// use jsonrpc::{json_rpc, RpcServerResult};
+/// use jsonrpc::router::Dispatcher;
+///
+/// json_rpc! {
+///     pub service CalculatorService {
+///         dispatcher CalculatorServiceDispatcher;
+///         client CalculatorClient;
+///
+///         rpc fn add(a: i64, b: i64) -> i64;
+///         notify fn touch(msg: String);
+///     }
+/// }
+///
+/// struct Calculator;
+///
+/// impl CalculatorService for Calculator {
+///     fn add(&self, a: i64, b: i64) -> RpcServerResult<i64> {
+///         Ok(a + b)
+///     }
+///
+///     fn touch(&self, _msg: String) -> RpcServerResult<()> {
+///         Ok(())
+///     }
+/// }
+///
+/// let dispatcher = CalculatorServiceDispatcher::new(Calculator);
+/// let req = serde_json::from_str(r#"{"jsonrpc":"2.0","method":"add","params":{"a":1,"b":2},"id":1}"#).unwrap();
+/// let resp = dispatcher.dispatch(req).unwrap();
+/// assert_eq!(serde_json::json!(3), resp.into_result().unwrap());
+/// ```
+///
+/// expands to
+///
+/// * the `CalculatorService` trait, with one `&self` method per entry returning `RpcServerResult`,
+/// * `CalculatorServiceDispatcher<S>`, a `Dispatcher` taking params either by position or by name,
+///   and answering `MethodNotFound` for anything else,
+/// * `CalculatorClient<T>`, a typed stub over a `Client<T>`, which sends `rpc` methods as requests
+///   and `notify` methods as notifications.
+#[macro_export]
+macro_rules! json_rpc {
+    (@ret) => { () };
+    (@ret $ret:ty) => { $ret };
+
+    (@client_method $vis:vis rpc $method:ident ($($arg:ident : $ty:ty),*) $(-> $ret:ty)?) => {
+        $vis fn $method(&mut self, $($arg: $ty),*) -> $crate::RpcResult<$crate::json_rpc!(@ret $($ret)?)> {
+            self.client.call(stringify!($method), ($($arg,)*))
+        }
+    };
+    (@client_method $vis:vis notify $method:ident ($($arg:ident : $ty:ty),*)) => {
+        $vis fn $method(&mut self, $($arg: $ty),*) -> $crate::RpcResult<()> {
+            self.client.notify(stringify!($method), ($($arg,)*))
+        }
+    };
+
+    (
+        $(#[$attr:meta])*
+        $vis:vis service $service:ident {
+            dispatcher $dispatcher:ident;
+            client $client:ident;
+
+            $(
+                $(#[$mattr:meta])*
+                $kind:ident fn $method:ident ($($arg:ident : $ty:ty),* $(,)?) $(-> $ret:ty)?;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis trait $service {
+            $(
+                $(#[$mattr])*
+                fn $method(&self, $($arg: $ty),*) -> $crate::RpcServerResult<$crate::json_rpc!(@ret $($ret)?)>;
+            )*
+        }
+
+        $vis struct $dispatcher<S> {
+            service: S,
+        }
+
+        impl<S: $service> $dispatcher<S> {
+            $vis fn new(service: S) -> $dispatcher<S> {
+                $dispatcher {
+                    service,
+                }
+            }
+
+            $vis fn service(&self) -> &S {
+                &self.service
+            }
+        }
+
+        impl<S: $service> $crate::router::Dispatcher for $dispatcher<S> {
+            fn dispatch(&self, req: $crate::proto::Request) -> Option<$crate::proto::Response> {
+                let result = match &req.method[..] {
+                    $(
+                        stringify!($method) => {
+                            $crate::macros::invoke(req.params, &[$(stringify!($arg)),*], |_params| {
+                                $( let $arg: $ty = _params.take()?; )*
+                                self.service.$method($($arg),*)
+                            })
+                        },
+                    )*
+                    _ => Err($crate::macros::method_not_found(&req.method)),
+                };
+
+                req.id.map(|id| $crate::proto::Response::new(result, id))
+            }
+        }
+
+        $vis struct $client<T> {
+            client: $crate::client::Client<T>,
+        }
+
+        impl<T> $client<T>
+            where T: $crate::proto::trans::SendRequest + $crate::proto::trans::GetResponse
+        {
+            $vis fn new(transport: T) -> $client<T> {
+                $client {
+                    client: $crate::client::Client::new(transport),
+                }
+            }
+
+            $vis fn into_inner(self) -> T {
+                self.client.into_inner()
+            }
+
+            $(
+                $crate::json_rpc!(@client_method $vis $kind $method ($($arg: $ty),*) $(-> $ret)?);
+            )*
+        }
+    };
+}
+
+pub fn invoke<R, F>(params: Option<Value>, names: &'static [&'static str], f: F) -> RpcServerResult<Value>
+    where R: Serialize,
+          F: FnOnce(&mut Params) -> RpcServerResult<R>
+{
+    let mut params = Params::new(params, names)?;
+    let result = f(&mut params)?;
+    serde_json::to_value(result)
+        .map_err(|err| errors::InternalError::with_detail(err.to_string()))
+}
+
+pub fn method_not_found(method: &str) -> ProtocolError {
+    errors::MethodNotFound::with_detail(Value::String(format!("Unknown method {:?}", method)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use serde_json::{self, Value};
+
+    use crate::{Error, RpcServerResult};
+    use crate::proto::{self, Request, Response};
+    use crate::proto::spec::errors;
+    use crate::proto::trans::{ClientRequest, GetResponse, SendRequest, ServerResponse};
+    use crate::router::Dispatcher;
+
+    json_rpc! {
+        service Calculator {
+            dispatcher CalculatorDispatcher;
+            client CalculatorClient;
+
+            rpc fn add(a: i64, b: Option<i64>) -> i64;
+            rpc fn version() -> String;
+            notify fn touch(msg: String);
+        }
+    }
+
+    #[derive(Default)]
+    struct MyCalculator {
+        touched: Mutex<Vec<String>>,
+    }
+
+    impl Calculator for MyCalculator {
+        fn add(&self, a: i64, b: Option<i64>) -> RpcServerResult<i64> {
+            a.checked_add(b.unwrap_or(0)).ok_or_else(|| errors::ServerError::new(-32000))
+        }
+
+        fn version(&self) -> RpcServerResult<String> {
+            Ok("1.0".to_owned())
+        }
+
+        fn touch(&self, msg: String) -> RpcServerResult<()> {
+            self.touched.lock().unwrap().push(msg);
+            Ok(())
+        }
+    }
+
+    // Dispatches every request in place and keeps the responses for `get_response`
+    struct Loopback<D> {
+        dispatcher: D,
+        responses: VecDeque<ServerResponse>,
+    }
+
+    impl<D: Dispatcher> SendRequest for Loopback<D> {
+        fn request(&mut self, request: Request) -> proto::Result<()> {
+            let encoded = serde_json::to_string(&request).unwrap();
+            let resp = self.dispatcher.dispatch_request(serde_json::from_str(&encoded).unwrap());
+            self.responses.extend(resp);
+            Ok(())
+        }
+
+        fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+            let resp = self.dispatcher.dispatch_request(ClientRequest::Batch(requests.into_iter().map(Ok).collect()));
+            self.responses.extend(resp);
+            Ok(())
+        }
+    }
+
+    impl<D> GetResponse for Loopback<D> {
+        fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
+            Ok(self.responses.pop_front())
+        }
+    }
+
+    fn call(dispatcher: &CalculatorDispatcher<MyCalculator>, req: &str) -> Option<Response> {
+        dispatcher.dispatch(serde_json::from_str(req).unwrap())
+    }
+
+    #[test]
+    fn test_macro_dispatcher() {
+        let dispatcher = CalculatorDispatcher::new(MyCalculator::default());
+
+        let resp = call(&dispatcher, "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}");
+        assert_eq!(Some(Response::result(Value::from(3), 1)), resp);
+
+        let resp = call(&dispatcher, "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":{\"a\":1},\"id\":2}");
+        assert_eq!(Some(Response::result(Value::from(1), 2)), resp);
+
+        let resp = call(&dispatcher, "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2,3],\"id\":3}");
+        assert_eq!(errors::ERRCODE_INVALID_PARAMS, resp.unwrap().into_result().unwrap_err().code);
+
+        let resp = call(&dispatcher, "{\"jsonrpc\":\"2.0\",\"method\":\"sub\",\"id\":4}");
+        assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, resp.unwrap().into_result().unwrap_err().code);
+
+        let resp = call(&dispatcher, "{\"jsonrpc\":\"2.0\",\"method\":\"touch\",\"params\":[\"ping\"]}");
+        assert_eq!(None, resp);
+        assert_eq!(vec!["ping".to_owned()], *dispatcher.service().touched.lock().unwrap());
+    }
+
+    #[test]
+    fn test_macro_client() {
+        let mut client = CalculatorClient::new(Loopback {
+            dispatcher: CalculatorDispatcher::new(MyCalculator::default()),
+            responses: VecDeque::new(),
+        });
+
+        assert_eq!(5, client.add(2, Some(3)).unwrap());
+        assert_eq!("1.0", client.version().unwrap());
+        client.touch("ping".to_owned()).unwrap();
+
+        let transport = client.into_inner();
+        assert!(transport.responses.is_empty());
+        assert_eq!(vec!["ping".to_owned()], *transport.dispatcher.service().touched.lock().unwrap());
+
+        match CalculatorClient::new(transport).add(i64::MAX, Some(1)) {
+            Err(Error::ProtocolError(..)) => {},
+            other => panic!("Expecting an error for overflow, but found {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,452 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Endpoint that both issues and serves requests on one connection, as in LSP.
+
+use std::io::{self, BufReader, BufWriter};
+use std::net::TcpStream;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::RpcResult;
+use crate::client::MultiplexClient;
+use crate::client::multiplex::{tcp_closer, Closer, Waiter};
+use crate::client::pending::Pending;
+use crate::proto::{self, Id, InternalErrorKind, Request, Response};
+use crate::proto::spec::{PeerReader, PeerWriter};
+use crate::proto::trans::{ClientRequest, GetMessage, Message, SendRequest, SendResponse, ServerResponse};
+use crate::router::{dispatch_caught, CatchPanic, Dispatcher};
+
+/// One end of a connection where both sides call each other.
+///
+/// A background thread reads every message. Responses go to the call waiting for their `Id`, as
+/// in `MultiplexClient`, and requests go to the local dispatcher. Requests are dispatched on a pool
+/// of worker threads, and wait in line while every worker is busy. Notifications are dispatched in
+/// order on a thread of their own. Either kind of handler may call back into the remote side
+/// while it runs, and one that panics is answered with an `InternalError`.
+///
+/// A message that cannot be decoded is answered with an error and a null id, and reading goes on
+/// unless the stream is out of sync.
+///
+/// A peer made by `from_tcp` shuts its connection down once the last clone is dropped, or on
+/// `close`.
+#[derive(Clone)]
+pub struct Peer {
+    client: MultiplexClient,
+}
+
+impl Peer {
+    /// Creates a peer dispatching requests on one worker per CPU.
+    pub fn new<R, W, D>(reader: R, writer: W, dispatcher: D) -> Peer
+        where R: GetMessage + Send + 'static,
+              W: SendRequest + SendResponse + Send + 'static,
+              D: Dispatcher + Send + Sync + 'static
+    {
+        Peer::with_workers(reader, writer, dispatcher, default_workers())
+    }
+
+    /// Creates a peer dispatching at most `workers` requests at a time.
+    pub fn with_workers<R, W, D>(reader: R, writer: W, dispatcher: D, workers: usize) -> Peer
+        where R: GetMessage + Send + 'static,
+              W: SendRequest + SendResponse + Send + 'static,
+              D: Dispatcher + Send + Sync + 'static
+    {
+        Peer::spawn(reader, writer, dispatcher, workers, None)
+    }
+
+    fn spawn<R, W, D>(reader: R, writer: W, dispatcher: D, workers: usize, closer: Option<Closer>) -> Peer
+        where R: GetMessage + Send + 'static,
+              W: SendRequest + SendResponse + Send + 'static,
+              D: Dispatcher + Send + Sync + 'static
+    {
+        assert!(workers > 0, "a peer needs at least one worker");
+
+        let writer = Arc::new(Mutex::new(writer));
+        let client = MultiplexClient::with_closer(SharedWriter(writer.clone()), closer);
+        let dispatcher = Arc::new(dispatcher);
+
+        let (notifications, receiver) = mpsc::channel();
+        {
+            let dispatcher = dispatcher.clone();
+            thread::spawn(move|| notify(receiver, dispatcher));
+        }
+
+        // Workers stop once the reader drops the sender
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let writer = writer.clone();
+            let dispatcher = dispatcher.clone();
+            thread::spawn(move|| work(receiver, writer, dispatcher));
+        }
+
+        // The reader must not keep the client alive, or dropping the peer would not close it
+        let pending = client.pending();
+        thread::spawn(move|| read_messages(reader, writer, sender, notifications, pending));
+
+        Peer { client }
+    }
+
+    pub fn from_tcp<D>(stream: TcpStream, dispatcher: D) -> io::Result<Peer>
+        where D: Dispatcher + Send + Sync + 'static
+    {
+        let reader = PeerReader::new(BufReader::new(stream.try_clone()?));
+        let closer = tcp_closer(&stream)?;
+        let writer = PeerWriter::new(BufWriter::new(stream));
+        Ok(Peer::spawn(reader, writer, dispatcher, default_workers(), Some(closer)))
+    }
+
+    /// Shuts the connection of a peer made by `from_tcp` down, failing the calls in flight.
+    ///
+    /// Other peers are only closed by their transport.
+    pub fn close(&self) {
+        self.client.shutdown();
+    }
+
+    /// Calls `method` on the remote side, see `Client::call`.
+    pub fn call<P, R>(&self, method: &str, params: P) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        self.client.call(method, params)
+    }
+
+    pub fn call_timeout<P, R>(&self, method: &str, params: P, timeout: Duration) -> RpcResult<R>
+        where P: Serialize,
+              R: DeserializeOwned
+    {
+        self.client.call_timeout(method, params, timeout)
+    }
+
+    pub fn notify<P: Serialize>(&self, method: &str, params: P) -> RpcResult<()> {
+        self.client.notify(method, params)
+    }
+
+    /// The client issuing this end's calls, sharing the connection with the dispatcher.
+    pub fn client(&self) -> &MultiplexClient {
+        &self.client
+    }
+}
+
+fn default_workers() -> usize {
+    thread::available_parallelism().map_or(4, NonZeroUsize::get)
+}
+
+/// Lets the client and the request handlers take turns on the same writer.
+pub(crate) struct SharedWriter<W>(pub(crate) Arc<Mutex<W>>);
+
+impl<W: SendRequest> SendRequest for SharedWriter<W> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        self.0.lock().unwrap().request(request)
+    }
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        self.0.lock().unwrap().batch_request(requests)
+    }
+}
+
+impl<W: SendResponse> SendResponse for SharedWriter<W> {
+    fn response(&mut self, response: Response) -> proto::Result<()> {
+        self.0.lock().unwrap().response(response)
+    }
+
+    fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
+        self.0.lock().unwrap().batch_response(responses)
+    }
+}
+
+fn work<W, D>(receiver: Arc<Mutex<Receiver<ClientRequest>>>, writer: Arc<Mutex<W>>, dispatcher: Arc<D>)
+    where W: SendResponse,
+          D: Dispatcher
+{
+    loop {
+        // The lock is released as soon as a request is taken
+        let req = match receiver.lock().unwrap().recv() {
+            Ok(req) => req,
+            Err(..) => break,
+        };
+
+        // A dispatcher of its own batches may still panic, which only costs that batch
+        let resp = match panic::catch_unwind(AssertUnwindSafe(|| CatchPanic(&*dispatcher).dispatch_request(req))) {
+            Ok(resp) => resp,
+            Err(..) => {
+                error!("Dropping a batch whose dispatcher panicked");
+                continue;
+            }
+        };
+        if let Some(resp) = resp {
+            trace!("Response {:?}", resp);
+            if let Err(err) = writer.lock().unwrap().server_response(resp) {
+                warn!("Failed to send response: {}", err);
+            }
+        }
+    }
+}
+
+/// Dispatches notifications in turn, so that they are seen in the order they were sent.
+fn notify<D: Dispatcher>(receiver: Receiver<Request>, dispatcher: Arc<D>) {
+    for req in receiver {
+        dispatch_caught(&*dispatcher, req);
+    }
+}
+
+fn read_messages<R, W>(mut reader: R,
+                       writer: Arc<Mutex<W>>,
+                       requests: mpsc::Sender<ClientRequest>,
+                       notifications: mpsc::Sender<Request>,
+                       pending: Arc<Mutex<Pending<Waiter>>>)
+    where R: GetMessage,
+          W: SendResponse
+{
+    let reason = loop {
+        match reader.get_message() {
+            Ok(Some(Message::Response(ServerResponse::Single(resp)))) => pending.lock().unwrap().deliver(resp),
+            Ok(Some(Message::Response(ServerResponse::Batch(resps)))) => {
+                let mut pending = pending.lock().unwrap();
+                for resp in resps {
+                    pending.deliver(resp);
+                }
+            },
+            Ok(Some(Message::Request(ClientRequest::Single(req)))) if req.id.is_none() => {
+                trace!("Notification {:?}", req);
+                if notifications.send(req).is_err() {
+                    break "notification dispatcher stopped".to_owned();
+                }
+            },
+            Ok(Some(Message::Request(req))) => {
+                trace!("Request {:?}", req);
+                if requests.send(req).is_err() {
+                    break "no worker left to dispatch requests".to_owned();
+                }
+            },
+            Ok(None) => break "connection closed by peer".to_owned(),
+            Err(err @ proto::Error::IoError(..)) => break err.to_string(),
+            Err(proto::Error::InternalError(ref err)) if matches!(err.kind(), InternalErrorKind::InvalidResponse) => {
+                warn!("Dropping invalid response: {}", err);
+            },
+            Err(err) => {
+                // Nothing tells which request it was, so the error goes back with a null id
+                let resp = Response::error(err.to_protocol_error(), Id::Null);
+                if let Err(err) = writer.lock().unwrap().response(resp) {
+                    break err.to_string();
+                }
+                // The stream cannot be trusted to be at a message boundary any more
+                if !err.is_recoverable() {
+                    break err.to_string();
+                }
+            },
+        }
+    };
+
+    pending.lock().unwrap().close(reason);
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, BufReader, BufWriter, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc, Mutex, OnceLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use crate::Error;
+    use crate::proto::{Id, Response};
+    use crate::proto::spec::{errors, ClientReader, PeerReader, PeerWriter};
+    use crate::proto::trans::{GetResponse, ServerResponse};
+    use crate::router::Router;
+
+    use super::Peer;
+
+    fn connect() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connecting = thread::spawn(move|| TcpStream::connect(addr).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        (stream, connecting.join().unwrap())
+    }
+
+    #[test]
+    fn test_peer_callback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+
+        let connecting = thread::spawn(move|| TcpStream::connect(addr).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let server = Peer::from_tcp(stream, router).unwrap();
+
+        // Doubles a number by asking the other side to add it to itself
+        let server_handle = Arc::new(OnceLock::new());
+        let mut router = Router::new();
+        {
+            let server_handle = server_handle.clone();
+            router.add_method("double", move |(n,): (i64,)| {
+                let peer: &Peer = server_handle.get().unwrap();
+                peer.call::<_, i64>("add", (n, n)).map_err(|err| errors::InternalError::with_detail(format!("{:?}", err)))
+            });
+        }
+        let stream = connecting.join().unwrap();
+        let conn = stream.try_clone().unwrap();
+        let client = Peer::from_tcp(stream, router).unwrap();
+        server_handle.set(client.clone()).ok().unwrap();
+
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+        assert_eq!(42, server.call::<_, i64>("double", (21,)).unwrap());
+
+        let workers = (0..4).map(|i| {
+            let server = server.clone();
+            thread::spawn(move|| server.call::<_, i64>("double", (i,)).unwrap())
+        }).collect::<Vec<_>>();
+        for (i, worker) in workers.into_iter().enumerate() {
+            assert_eq!(2 * i as i64, worker.join().unwrap());
+        }
+
+        match client.call::<_, i64>("double", (1,)) {
+            Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, err.code),
+            other => panic!("Expecting MethodNotFound, but found {:?}", other),
+        }
+
+        conn.shutdown(Shutdown::Both).unwrap();
+        match server.call::<_, i64>("double", (1,)) {
+            Err(Error::IoError(ref err)) if err.kind() == io::ErrorKind::ConnectionAborted => {},
+            other => panic!("Expecting the call to be aborted, but found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_peer_invalid_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connecting = thread::spawn(move|| TcpStream::connect(addr).unwrap());
+        let (stream, _) = listener.accept().unwrap();
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+        let _peer = Peer::with_workers(PeerReader::new(BufReader::new(stream.try_clone().unwrap())),
+                                       PeerWriter::new(BufWriter::new(stream)),
+                                       router,
+                                       1);
+
+        let mut stream = connecting.join().unwrap();
+        stream.write_all(b"{\"jsonrpc\":\"2.0\",,\"id\":1}\r\n\
+                           {\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":2}\r\n").unwrap();
+
+        // The syntax error is answered with a null id, and the next request is still served
+        let mut reader = ClientReader::new(BufReader::new(stream));
+        match reader.get_response().unwrap() {
+            Some(ServerResponse::Single(resp)) => {
+                assert_eq!(&Id::Null, resp.id());
+                assert_eq!(errors::ERRCODE_PARSE_ERROR, resp.into_result().unwrap_err().code);
+            },
+            other => panic!("Expecting an error, but found {:?}", other),
+        }
+        assert_eq!(Some(ServerResponse::Single(Response::result(Value::from(3), 2))), reader.get_response().unwrap());
+    }
+
+    #[test]
+    fn test_peer_notification_callback() {
+        let (server_stream, client_stream) = connect();
+
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+        let server = Peer::from_tcp(server_stream, router).unwrap();
+
+        // The notification handler calls back into the other side, and reports the result
+        let client_handle = Arc::new(OnceLock::<Peer>::new());
+        let (sender, receiver) = mpsc::channel();
+        let mut router = Router::new();
+        {
+            let client_handle = client_handle.clone();
+            let sender = Mutex::new(sender);
+            router.add_method("sum", move |(a, b): (i64, i64)| {
+                let sum = client_handle.get().unwrap().call_timeout::<_, i64>("add", (a, b), Duration::from_secs(5));
+                sender.lock().unwrap().send(sum.map_err(|err| format!("{:?}", err))).unwrap();
+                Ok(())
+            });
+        }
+        let client = Peer::from_tcp(client_stream, router).unwrap();
+        client_handle.set(client.clone()).ok().unwrap();
+
+        server.notify("sum", (1, 2)).unwrap();
+        assert_eq!(Ok(3), receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+    }
+
+    #[test]
+    fn test_peer_panic() {
+        let (server_stream, mut client_stream) = connect();
+
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b))
+              .add_method("panic", |()| -> crate::RpcServerResult<()> { panic!("on purpose") });
+        let peer = Peer::with_workers(PeerReader::new(BufReader::new(server_stream.try_clone().unwrap())),
+                                      PeerWriter::new(BufWriter::new(server_stream)),
+                                      router,
+                                      1);
+
+        // Neither the worker nor the notification thread is lost to a panic
+        client_stream.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"panic\"}\r\n\
+                                  {\"jsonrpc\":\"2.0\",\"method\":\"panic\",\"id\":1}\r\n\
+                                  {\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":2}\r\n").unwrap();
+
+        let mut reader = ClientReader::new(BufReader::new(client_stream.try_clone().unwrap()));
+        match reader.get_response().unwrap() {
+            Some(ServerResponse::Single(resp)) => {
+                assert_eq!(&Id::Number(1), resp.id());
+                assert_eq!(errors::ERRCODE_INTERNAL_ERROR, resp.into_result().unwrap_err().code);
+            },
+            other => panic!("Expecting an error, but found {:?}", other),
+        }
+        assert_eq!(Some(ServerResponse::Single(Response::result(Value::from(3), 2))), reader.get_response().unwrap());
+        drop(peer);
+    }
+
+    #[test]
+    fn test_peer_close() {
+        let (server_stream, mut client_stream) = connect();
+
+        // Dropping the last handle closes the connection
+        let peer = Peer::from_tcp(server_stream, Router::new()).unwrap();
+        let other = peer.clone();
+        drop(peer);
+        drop(other);
+        assert_eq!(0, client_stream.read(&mut [0u8; 16]).unwrap());
+
+        // And so does `close`, failing the calls made after it
+        let (server_stream, mut client_stream) = connect();
+        let peer = Peer::from_tcp(server_stream, Router::new()).unwrap();
+        peer.close();
+        assert_eq!(0, client_stream.read(&mut [0u8; 16]).unwrap());
+        assert!(peer.call::<_, Value>("echo", ()).is_err());
+    }
+}
@@ -0,0 +1,768 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Delimiting JSON-RPC messages on a byte stream.
+//!
+//! The spec readers and writers are parameterized over a `Framing`, which only deals with where a
+//! message starts and ends. Encoding and validating the JSON-RPC message itself is left to them.
+
+use std::io::{self, Read, Write};
+
+use serde_json::{self, Value};
+
+use crate::proto::{self, InternalError, InternalErrorKind};
+
+/// Splits a byte stream into JSON messages.
+pub trait Framing {
+    /// Reads the next message, returning `None` on a clean EOF between two frames.
+    fn read_message<R: Read>(&self, reader: &mut R) -> proto::Result<Option<Value>>;
+
+    /// Writes `payload`, one encoded JSON message, as a frame.
+    fn write_message<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()>;
+}
+
+/// JSON values back to back, found by parsing the stream itself.
+///
+/// Every value written is followed by `\r\n`, which is only whitespace to this reader, so that
+/// peers expecting newline-delimited messages can read it as well. After a syntax error, the rest
+/// of the line is skipped, so that reading resumes with the message after it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Concatenated;
+
+impl Framing for Concatenated {
+    fn read_message<R: Read>(&self, reader: &mut R) -> proto::Result<Option<Value>> {
+        let next = serde_json::Deserializer::from_reader(&mut *reader).into_iter::<Value>().next();
+        match next {
+            Some(Ok(value)) => Ok(Some(value)),
+            Some(Err(err)) => {
+                if err.is_io() {
+                    return Err(proto::Error::IoError(err.into()));
+                }
+
+                // A column of 0 means the offending byte was the line break itself
+                if !err.is_eof() && err.column() > 0 {
+                    skip_line(reader)?;
+                }
+                Err(proto::Error::ParserError(err))
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn write_message<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        write_line(writer, payload)
+    }
+}
+
+/// One message per line, terminated by `\n` or `\r\n`. Blank lines are skipped.
+///
+/// A line longer than `DEFAULT_MAX_FRAME_SIZE` is an invalid frame. It is rejected as soon as the
+/// limit is reached, and the rest of it is skipped without being buffered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Newline;
+
+impl Framing for Newline {
+    fn read_message<R: Read>(&self, reader: &mut R) -> proto::Result<Option<Value>> {
+        read_line(reader, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    fn write_message<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        write_line(writer, payload)
+    }
+}
+
+/// Messages preceded by a `Content-Length: N\r\n\r\n` header, as used by the Language Server
+/// Protocol and the Debug Adapter Protocol.
+///
+/// Header names are matched case-insensitively and unknown headers are ignored. An optional
+/// `Content-Type` is accepted as long as its charset, if any, is UTF-8. Anything else in the
+/// headers is an invalid frame, which is answered with `ParseError`. As with `LengthPrefixed`, a
+/// length over the maximum frame size is rejected before anything is allocated for it.
+///
+/// The payload of an invalid frame is skipped when its length is known. Otherwise the end of the
+/// frame cannot be found, and the error is of kind `LostSync`. A header line over 8 KiB fails the
+/// stream with an `InvalidData` I/O error.
+#[derive(Debug, Clone, Copy)]
+pub struct ContentLength {
+    content_type: Option<&'static str>,
+    max_frame_size: usize,
+}
+
+impl ContentLength {
+    pub fn new() -> ContentLength {
+        ContentLength {
+            content_type: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> ContentLength {
+        ContentLength {
+            content_type: None,
+            max_frame_size,
+        }
+    }
+
+    /// Also writes a `Content-Type` header before every message.
+    ///
+    /// ```
+    /// use jsonrpc::proto::framing::ContentLength;
+    ///
+    /// let framing = ContentLength::with_content_type("application/vscode-jsonrpc; charset=utf-8");
+    /// ```
+    pub fn with_content_type(content_type: &'static str) -> ContentLength {
+        ContentLength {
+            content_type: Some(content_type),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    pub fn content_type(&self) -> Option<&'static str> {
+        self.content_type
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: usize) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl Default for ContentLength {
+    fn default() -> ContentLength {
+        ContentLength::new()
+    }
+}
+
+impl Framing for ContentLength {
+    fn read_message<R: Read>(&self, reader: &mut R) -> proto::Result<Option<Value>> {
+        let mut length = Ok(None);
+        // The first problem is only reported after the headers, so that the payload can be skipped
+        let mut error = None;
+        let mut first = true;
+        loop {
+            let line = match read_header_line(reader) {
+                Ok(Some(line)) => line,
+                Ok(None) if first => return Ok(None),
+                Ok(None) => return Err(lost_sync("Stream ended in the middle of the headers".to_owned())),
+                Err(err @ proto::Error::IoError(..)) => return Err(err),
+                Err(err) => {
+                    first = false;
+                    error.get_or_insert(err);
+                    continue;
+                }
+            };
+            first = false;
+
+            if line.is_empty() {
+                break;
+            }
+
+            let (name, value) = match line.find(':') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => {
+                    error.get_or_insert(invalid_frame(format!("Malformed header {:?}", line)));
+                    continue;
+                }
+            };
+
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = match (length, value.parse::<usize>()) {
+                    (Ok(None), Ok(value)) => Ok(Some(value)),
+                    (Ok(Some(prev)), Ok(value)) if prev == value => {
+                        error.get_or_insert(invalid_frame("Duplicated Content-Length header".to_owned()));
+                        Ok(Some(prev))
+                    },
+                    (Ok(Some(..)), Ok(..)) => Err(format!("Conflicting Content-Length {:?}", value)),
+                    (Ok(..), Err(..)) => Err(format!("Invalid Content-Length {:?}", value)),
+                    (Err(detail), _) => Err(detail),
+                };
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                if let Err(err) = check_content_type(value) {
+                    error.get_or_insert(err);
+                }
+            }
+        }
+
+        let length = match length {
+            Ok(Some(length)) => length,
+            Ok(None) => return Err(lost_sync("Missing Content-Length header".to_owned())),
+            Err(detail) => return Err(lost_sync(detail)),
+        };
+
+        if length > self.max_frame_size {
+            skip_payload(reader, length)?;
+            return Err(oversized_frame(length, self.max_frame_size));
+        }
+        match error {
+            Some(err) => {
+                skip_payload(reader, length)?;
+                Err(err)
+            },
+            None => read_payload(reader, length).map(Some),
+        }
+    }
+
+    fn write_message<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 64);
+        write!(frame, "Content-Length: {}\r\n", payload.len())?;
+        if let Some(content_type) = self.content_type {
+            write!(frame, "Content-Type: {}\r\n", content_type)?;
+        }
+        frame.extend_from_slice(b"\r\n");
+        frame.extend_from_slice(payload);
+        writer.write_all(&frame)
+    }
+}
+
+/// Accepts any media type, but only UTF-8 content. `utf8` is still sent by some older LSP clients.
+fn check_content_type(content_type: &str) -> proto::Result<()> {
+    for param in content_type.split(';').skip(1) {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next().unwrap_or_default().trim();
+        let value = kv.next().unwrap_or_default().trim().trim_matches('"');
+
+        if key.eq_ignore_ascii_case("charset")
+            && !value.eq_ignore_ascii_case("utf-8") && !value.eq_ignore_ascii_case("utf8") {
+            return Err(invalid_frame(format!("Unsupported charset {:?}", value)));
+        }
+    }
+    Ok(())
+}
+
+/// Longest header line accepted by `ContentLength`, in bytes.
+const MAX_HEADER_LINE: usize = 8 * 1024;
+
+/// Largest payload accepted by `Newline`, `LengthPrefixed::new` and `ContentLength::new`, in bytes.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// Messages preceded by their length, as a 4 bytes big-endian integer.
+///
+/// The reader never has to scan the payload to find where it ends. A frame longer than the
+/// maximum frame size is rejected before anything is allocated for it, and its payload is skipped
+/// so that the next message can still be read.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthPrefixed {
+    max_frame_size: usize,
+}
+
+impl LengthPrefixed {
+    pub fn new() -> LengthPrefixed {
+        LengthPrefixed::with_max_frame_size(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    pub fn with_max_frame_size(max_frame_size: usize) -> LengthPrefixed {
+        LengthPrefixed {
+            max_frame_size,
+        }
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+}
+
+impl Default for LengthPrefixed {
+    fn default() -> LengthPrefixed {
+        LengthPrefixed::new()
+    }
+}
+
+impl Framing for LengthPrefixed {
+    fn read_message<R: Read>(&self, reader: &mut R) -> proto::Result<Option<Value>> {
+        let mut prefix = [0u8; 4];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match reader.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(invalid_frame("Stream ended in the middle of a length prefix".to_owned())),
+                Ok(n) => filled += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(proto::Error::IoError(err)),
+            }
+        }
+
+        let length = u32::from_be_bytes(prefix) as usize;
+        if length > self.max_frame_size {
+            skip_payload(reader, length)?;
+            return Err(oversized_frame(length, self.max_frame_size));
+        }
+
+        read_payload(reader, length).map(Some)
+    }
+
+    fn write_message<W: Write>(&self, writer: &mut W, payload: &[u8]) -> io::Result<()> {
+        if payload.len() > self.max_frame_size || payload.len() > u32::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Message of {} bytes exceeds the limit of {} bytes",
+                                              payload.len(), self.max_frame_size.min(u32::MAX as usize))));
+        }
+
+        writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+        writer.write_all(payload)
+    }
+}
+
+/// Reads the next message with `framing`, so that a read timeout does not lose its first bytes.
+///
+/// When reading times out, every byte consumed so far is kept in `partial`, and the next call
+/// reads them again before the stream. The message is then parsed from its start once the rest
+/// arrives, whatever the framing.
+pub(crate) fn read_resumable<F, R>(framing: &F, reader: &mut R, partial: &mut Vec<u8>) -> proto::Result<Option<Value>>
+    where F: Framing,
+          R: Read
+{
+    let mut replay = Replay {
+        reader,
+        consumed: partial,
+        pos: 0,
+    };
+    let result = framing.read_message(&mut replay);
+
+    match result {
+        Err(proto::Error::IoError(ref err))
+            if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {},
+        _ => partial.clear(),
+    }
+    result
+}
+
+/// Replays `consumed[pos..]` before reading on, and records everything read from `reader`.
+struct Replay<'a, R> {
+    reader: &'a mut R,
+    consumed: &'a mut Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for Replay<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.consumed.len() {
+            let n = buf.len().min(self.consumed.len() - self.pos);
+            buf[..n].copy_from_slice(&self.consumed[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+
+        let n = self.reader.read(buf)?;
+        self.consumed.extend_from_slice(&buf[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn invalid_frame(detail: String) -> proto::Error {
+    let ierr = InternalError::new(InternalErrorKind::InvalidFrame, "Invalid frame", Some(detail));
+    proto::Error::InternalError(ierr)
+}
+
+fn oversized_frame(length: usize, max_frame_size: usize) -> proto::Error {
+    invalid_frame(format!("Frame of {} bytes exceeds the limit of {} bytes", length, max_frame_size))
+}
+
+/// Skips the payload of an invalid frame, so that the next message can still be read.
+fn skip_payload<R: Read>(reader: &mut R, length: usize) -> proto::Result<()> {
+    let skipped = io::copy(&mut reader.take(length as u64), &mut io::sink())?;
+    if skipped < length as u64 {
+        return Err(invalid_frame(format!("Stream ended before the end of a frame of {} bytes", length)));
+    }
+    Ok(())
+}
+
+pub(crate) fn lost_sync(detail: String) -> proto::Error {
+    let ierr = InternalError::new(InternalErrorKind::LostSync, "Invalid frame", Some(detail));
+    proto::Error::InternalError(ierr)
+}
+
+fn parse_payload(payload: &[u8]) -> proto::Result<Value> {
+    serde_json::from_slice(payload).map_err(proto::Error::ParserError)
+}
+
+fn read_payload<R: Read>(reader: &mut R, length: usize) -> proto::Result<Value> {
+    let mut payload = vec![0u8; length];
+    match reader.read_exact(&mut payload) {
+        Ok(()) => parse_payload(&payload),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(invalid_frame(format!("Stream ended before the end of a frame of {} bytes", length)))
+        },
+        Err(err) => Err(proto::Error::IoError(err)),
+    }
+}
+
+// Reads byte by byte, so that nothing past the frame is taken from the reader
+fn read_byte<R: Read>(reader: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(..) => return Ok(Some(byte[0])),
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads a `Newline` frame, rejecting it as soon as it grows past `max_frame_size`.
+fn read_line<R: Read>(reader: &mut R, max_frame_size: usize) -> proto::Result<Option<Value>> {
+    let mut line = Vec::new();
+    loop {
+        match read_byte(reader)? {
+            Some(b'\n') => {
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    line.clear();
+                    continue;
+                }
+                return parse_payload(&line).map(Some);
+            },
+            Some(_) if line.len() >= max_frame_size => {
+                skip_line(reader)?;
+                return Err(invalid_frame(format!("Line exceeds the limit of {} bytes", max_frame_size)));
+            },
+            Some(b) => line.push(b),
+            None if line.iter().all(u8::is_ascii_whitespace) => return Ok(None),
+            None => {
+                return Err(invalid_frame(format!("Stream ended in the middle of a line of {} bytes",
+                                                 line.len())));
+            }
+        }
+    }
+}
+
+/// Skips the rest of a line, up to and including its `\n`, without buffering it.
+fn skip_line<R: Read>(reader: &mut R) -> io::Result<()> {
+    while let Some(b) = read_byte(reader)? {
+        if b == b'\n' {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a payload followed by `\r\n`, as a single write.
+fn write_line<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 2);
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(b"\r\n");
+    writer.write_all(&frame)
+}
+
+/// Reads a header line without its `\r\n`, returning `None` on EOF before its first byte.
+///
+/// A line longer than `MAX_HEADER_LINE` fails with `InvalidData`, as the end of the frame is lost.
+fn read_header_line<R: Read>(reader: &mut R) -> proto::Result<Option<String>> {
+    let mut line = Vec::new();
+    loop {
+        match read_byte(reader)? {
+            Some(b'\n') => break,
+            Some(_) if line.len() >= MAX_HEADER_LINE => {
+                return Err(proto::Error::IoError(io::Error::new(io::ErrorKind::InvalidData,
+                                                                format!("Header line exceeds the limit of {} bytes",
+                                                                        MAX_HEADER_LINE))));
+            },
+            Some(b) => line.push(b),
+            None if line.is_empty() => return Ok(None),
+            None => return Err(invalid_frame("Stream ended in the middle of a header".to_owned())),
+        }
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| invalid_frame("Header is not valid UTF-8".to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read, Write};
+
+    use serde_json::Value;
+
+    use crate::proto::{self, Id, InternalErrorKind, Request, Response};
+    use crate::proto::spec::{errors, ClientReader, ClientStream, ClientWriter, ServerReader, ServerStream};
+    use crate::router::Router;
+    use crate::server;
+    use crate::proto::trans::{ClientRequest, GetRequest, GetResponse, SendRequest, SendResponse, ServerResponse};
+
+    use super::{Concatenated, ContentLength, Framing, LengthPrefixed, Newline};
+
+    fn round_trip<F: Framing + Copy>(framing: F) -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut writer = ClientWriter::with_framing(&mut buf, framing);
+            writer.request(Request::new("echo".to_owned(), Some(Value::from(vec!["ping"])), Some(1))).unwrap();
+            writer.batch_request(vec![Request::new_notify("touch".to_owned(), None)]).unwrap();
+        }
+
+        let mut reader = ServerReader::with_framing(Cursor::new(buf.clone()), framing);
+        match reader.get_request().unwrap() {
+            Some(ClientRequest::Single(req)) => assert_eq!("echo", req.method),
+            other => panic!("Expecting a request, but found {:?}", other),
+        }
+        match reader.get_request().unwrap() {
+            Some(ClientRequest::Batch(reqs)) => assert_eq!(1, reqs.len()),
+            other => panic!("Expecting a batch, but found {:?}", other),
+        }
+        assert!(reader.get_request().unwrap().is_none());
+
+        buf
+    }
+
+    fn assert_invalid_frame<F: Framing>(framing: F, input: &[u8]) {
+        match framing.read_message(&mut Cursor::new(input)) {
+            Err(err) => {
+                match err {
+                    proto::Error::InternalError(ref ierr)
+                        if matches!(ierr.kind(), InternalErrorKind::InvalidFrame | InternalErrorKind::LostSync) => {},
+                    ref other => panic!("Expecting an invalid frame for {:?}, but found {:?}", input, other),
+                }
+                assert_eq!(errors::ERRCODE_PARSE_ERROR, err.to_protocol_error().code);
+            },
+            Ok(other) => panic!("Expecting an invalid frame for {:?}, but found {:?}", input, other),
+        }
+    }
+
+    // Reads from `input` and collects whatever gets written
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_framing_round_trip() {
+        let buf = round_trip(Concatenated);
+        assert!(buf.ends_with(b"}]\r\n"));
+
+        let buf = round_trip(Newline);
+        assert_eq!(2, buf.iter().filter(|&&b| b == b'\n').count());
+
+        let buf = round_trip(ContentLength::new());
+        assert!(buf.starts_with(b"Content-Length: 58\r\n\r\n{"));
+
+        let buf = round_trip(LengthPrefixed::new());
+        assert_eq!(&[0, 0, 0, 58], &buf[..4]);
+    }
+
+    // Counts the calls to `write`
+    #[derive(Default)]
+    struct Counting {
+        writes: usize,
+        output: Vec<u8>,
+    }
+
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn count_writes<F: Framing>(framing: F) -> usize {
+        let mut writer = Counting::default();
+        framing.write_message(&mut writer, b"{}").unwrap();
+        writer.writes
+    }
+
+    #[test]
+    fn test_framing_single_write() {
+        assert_eq!(1, count_writes(Concatenated));
+        assert_eq!(1, count_writes(Newline));
+        assert_eq!(1, count_writes(ContentLength::new()));
+    }
+
+    #[test]
+    fn test_framing_invalid() {
+        assert_invalid_frame(Newline, b"{\"jsonrpc\":\"2.0\"");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: 10\r\n");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: ten\r\n\r\n{}");
+        assert_invalid_frame(ContentLength::new(), b"Content-Type: application/json\r\n\r\n{}");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: 10\r\n\r\n{}");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length 2\r\n\r\n{}");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: 2\r\nContent-Length: 2\r\n\r\n{}");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: 2\r\nContent-Type: text/plain; charset=latin1\r\n\r\n{}");
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: 2\r\nX-\xff: 1\r\n\r\n{}");
+        assert_invalid_frame(LengthPrefixed::new(), b"\0\0");
+        assert_invalid_frame(LengthPrefixed::new(), b"\0\0\0\x0a{}");
+
+        // The rest of a malformed line is skipped, unless the error was on the line break
+        let mut input = Cursor::new(&b"{\"a\" 1}\r\n{\"b\":2}\r\n\"abc\n{\"c\":3}"[..]);
+        assert!(matches!(Concatenated.read_message(&mut input), Err(proto::Error::ParserError(..))));
+        assert_eq!(Some(serde_json::json!({"b": 2})), Concatenated.read_message(&mut input).unwrap());
+        assert!(matches!(Concatenated.read_message(&mut input), Err(proto::Error::ParserError(..))));
+        assert_eq!(Some(serde_json::json!({"c": 3})), Concatenated.read_message(&mut input).unwrap());
+        assert_eq!(None, Concatenated.read_message(&mut input).unwrap());
+
+        // Blank lines between messages are not frames
+        let mut input = Cursor::new(&b"\r\n{}\n\n[]\r\n \r\n"[..]);
+        assert_eq!(Some(Value::Object(Default::default())), Newline.read_message(&mut input).unwrap());
+        assert_eq!(Some(Value::Array(Vec::new())), Newline.read_message(&mut input).unwrap());
+        assert_eq!(None, Newline.read_message(&mut input).unwrap());
+    }
+
+    #[test]
+    fn test_framing_max_frame_size() {
+        let framing = LengthPrefixed::with_max_frame_size(8);
+
+        let mut input = Cursor::new(&b"\0\0\0\x0b[1,2,3,4,5]\0\0\0\x02{}"[..]);
+        match framing.read_message(&mut input) {
+            Err(proto::Error::InternalError(ref ierr)) if matches!(ierr.kind(), InternalErrorKind::InvalidFrame) => {},
+            other => panic!("Expecting an oversized frame, but found {:?}", other),
+        }
+        assert_eq!(Some(Value::Object(Default::default())), framing.read_message(&mut input).unwrap());
+
+        // Nothing is allocated for the claimed length of a frame over the limit
+        assert_invalid_frame(framing, b"\xff\xff\xff\xff{}");
+
+        let lsp = ContentLength::with_max_frame_size(8);
+        let mut input = Cursor::new(&b"Content-Length: 11\r\n\r\n[1,2,3,4,5]Content-Length: 2\r\n\r\n{}"[..]);
+        match lsp.read_message(&mut input) {
+            Err(proto::Error::InternalError(ref ierr)) if matches!(ierr.kind(), InternalErrorKind::InvalidFrame) => {},
+            other => panic!("Expecting an oversized frame, but found {:?}", other),
+        }
+        assert_eq!(Some(Value::Object(Default::default())), lsp.read_message(&mut input).unwrap());
+        assert_invalid_frame(ContentLength::new(), b"Content-Length: 18446744073709551615\r\n\r\n{}");
+
+        // Header lines are bounded as well, whatever the frame size
+        let mut input = b"X-Padding: ".to_vec();
+        input.resize(16 * 1024, b'-');
+        match ContentLength::new().read_message(&mut Cursor::new(input)) {
+            Err(proto::Error::IoError(ref err)) if err.kind() == io::ErrorKind::InvalidData => {},
+            other => panic!("Expecting an overlong header line, but found {:?}", other),
+        }
+
+        // An overlong line is rejected without being read to its end first
+        let mut input = Cursor::new(&b"[1,2,3,4,5]\r\n{}\n"[..]);
+        match super::read_line(&mut input, 8) {
+            Err(proto::Error::InternalError(ref ierr)) if matches!(ierr.kind(), InternalErrorKind::InvalidFrame) => {},
+            other => panic!("Expecting an oversized line, but found {:?}", other),
+        }
+        assert_eq!(Some(Value::Object(Default::default())), super::read_line(&mut input, 8).unwrap());
+
+        let mut buf = Vec::new();
+        let mut writer = ClientWriter::with_framing(&mut buf, framing);
+        match writer.request(Request::new_notify("touch".to_owned(), None)) {
+            Err(proto::Error::IoError(ref err)) if err.kind() == io::ErrorKind::InvalidInput => {},
+            other => panic!("Expecting the message to be rejected, but found {:?}", other),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_framing_lsp_resync() {
+        let request = "{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[3,4],\"id\":7}";
+        let input = format!("Content-Length: 2\r\nContent-Type: text/plain; charset=latin1\r\n\r\n{{}}\
+                             Content-Length 2\r\nContent-Length: 2\r\n\r\n{{}}\
+                             Content-Length: 2\r\nContent-Length: 2\r\n\r\n{{}}\
+                             Content-Length: {}\r\n\r\n{}", request.len(), request);
+
+        // Each invalid frame is answered, and the request after them still is
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+        let mut server = ServerStream::with_framing(Pipe { input: Cursor::new(input.into_bytes()), output: Vec::new() },
+                                                    ContentLength::new());
+        server::serve_connection(&mut server, &router).unwrap();
+
+        let output = server.into_inner().output;
+        let mut client = ClientReader::with_framing(Cursor::new(output), ContentLength::new());
+        for _ in 0..3 {
+            match client.get_response().unwrap() {
+                Some(ServerResponse::Single(resp)) => {
+                    assert_eq!(&Id::Null, resp.id());
+                    assert_eq!(errors::ERRCODE_PARSE_ERROR, resp.into_result().unwrap_err().code);
+                },
+                other => panic!("Expecting an error, but found {:?}", other),
+            }
+        }
+        match client.get_response().unwrap() {
+            Some(ServerResponse::Single(resp)) => assert_eq!(Response::result(Value::from(7), 7), resp),
+            other => panic!("Expecting a response, but found {:?}", other),
+        }
+        assert!(client.get_response().unwrap().is_none());
+
+        // Without a usable length, the end of the frame is lost and so is the connection
+        for input in [&b"Content-Length: ten\r\n\r\n{}Content-Length: 2\r\n\r\n{}"[..],
+                      &b"Content-Type: application/json\r\n\r\n{}Content-Length: 2\r\n\r\n{}"[..],
+                      &b"Content-Length: 2\r\nContent-Length: 3\r\n\r\n{}Content-Length: 2\r\n\r\n{}"[..]] {
+            let mut server = ServerStream::with_framing(Pipe { input: Cursor::new(input.to_vec()), output: Vec::new() },
+                                                        ContentLength::new());
+            match server::serve_connection(&mut server, &router) {
+                Err(ref err) if !err.is_recoverable() => {},
+                other => panic!("Expecting the connection to be dropped, but found {:?}", other),
+            }
+            assert!(!server.into_inner().output.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_framing_lsp() {
+        let request = "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"initialize\",\"params\":{}}";
+        let input = format!("content-length: {}\r\n\
+                             Content-Type: application/vscode-jsonrpc; charset=utf8\r\n\
+                             \r\n{}", request.len(), request);
+
+        let mut server = ServerStream::with_framing(Pipe { input: Cursor::new(input.into_bytes()), output: Vec::new() },
+                                                    ContentLength::new());
+        let req = match server.get_request().unwrap() {
+            Some(ClientRequest::Single(req)) => req,
+            other => panic!("Expecting a request, but found {:?}", other),
+        };
+        assert_eq!("initialize", req.method);
+        server.response(Response::result(Value::from("ok"), req.id.unwrap())).unwrap();
+        assert!(server.get_request().unwrap().is_none());
+
+        let output = server.into_inner().output;
+        assert_eq!(b"Content-Length: 38\r\n\r\n{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"ok\"}", &output[..]);
+
+        let framing = ContentLength::with_content_type("application/vscode-jsonrpc; charset=utf-8");
+        let mut client = ClientStream::with_framing(Pipe { input: Cursor::new(output), output: Vec::new() }, framing);
+        client.request(Request::new_notify("initialized".to_owned(), None)).unwrap();
+        match client.get_response().unwrap() {
+            Some(ServerResponse::Single(resp)) => assert_eq!(Response::result(Value::from("ok"), 1), resp),
+            other => panic!("Expecting a response, but found {:?}", other),
+        }
+
+        let expected = "Content-Length: 40\r\n\
+                        Content-Type: application/vscode-jsonrpc; charset=utf-8\r\n\
+                        \r\n{\"jsonrpc\":\"2.0\",\"method\":\"initialized\"}";
+        assert_eq!(expected, String::from_utf8(client.into_inner().output).unwrap());
+    }
+}
@@ -21,95 +21,319 @@
 //  DEALINGS IN THE SOFTWARE.
 
 use std::io;
+use std::fmt;
 use std::convert::From;
 
-use rustc_serialize::json::{Object, EncoderError, ParserError, Json, ToJson};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, DeserializeOwned};
+use serde::ser::SerializeStruct;
+use serde_json::{Map, Value};
 
+pub mod framing;
 pub mod spec;
 pub mod trans;
 
+/// Identifier of a request, echoed back by the matching response.
+///
+/// JSON-RPC 2.0 only allows integers, strings and `null`. A notification has no identifier at all,
+/// which is expressed as `Request::id` being `None` rather than with a variant of this type.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Id {
+    Number(i64),
+    String(String),
+    Null,
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Id::Number(n) => write!(f, "{}", n),
+            Id::String(s) => write!(f, "{:?}", s),
+            Id::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl From<i64> for Id {
+    fn from(n: i64) -> Id {
+        Id::Number(n)
+    }
+}
+
+impl From<i32> for Id {
+    fn from(n: i32) -> Id {
+        Id::Number(n as i64)
+    }
+}
+
+impl From<u32> for Id {
+    fn from(n: u32) -> Id {
+        Id::Number(n as i64)
+    }
+}
+
+impl From<String> for Id {
+    fn from(s: String) -> Id {
+        Id::String(s)
+    }
+}
+
+impl<'a> From<&'a str> for Id {
+    fn from(s: &'a str) -> Id {
+        Id::String(s.to_owned())
+    }
+}
+
+impl<I: Into<Id>> From<Option<I>> for Id {
+    fn from(id: Option<I>) -> Id {
+        id.map_or(Id::Null, |i| i.into())
+    }
+}
+
+impl From<Id> for Value {
+    fn from(id: Id) -> Value {
+        match id {
+            Id::Number(n) => Value::from(n),
+            Id::String(s) => Value::String(s),
+            Id::Null => Value::Null,
+        }
+    }
+}
+
+impl Serialize for Id {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match self {
+            Id::Number(n) => serializer.serialize_i64(*n),
+            Id::String(s) => serializer.serialize_str(s),
+            Id::Null => serializer.serialize_unit(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Id {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Id, D::Error> {
+        let id = Value::deserialize(deserializer)?;
+        spec::json_to_id(id).map_err(de::Error::custom)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct Request {
+pub struct Request<P = Value> {
     pub method: String,
-    pub params: Option<Json>,
-    pub id: Option<Json>,
+    pub params: Option<P>,
+    pub id: Option<Id>,
 }
 
-impl Request {
-    pub fn new<P: ToJson, I: ToJson>(method: String, params: Option<P>, id: Option<I>) -> Request {
+impl<P> Request<P> {
+    pub fn new<I: Into<Id>>(method: String, params: Option<P>, id: Option<I>) -> Request<P> {
         Request {
-                method: method,
-                params: params.map(|p| p.to_json()),
-                id: id.map(|i| i.to_json())
+                method,
+                params,
+                id: id.map(|i| i.into())
         }
 
     }
 
-    pub fn without_params<I: ToJson>(method: String, id: Option<I>) -> Request {
-        Request::new(method, None::<Json>, id)
-    }
-
-    pub fn new_notify<P: ToJson>(method: String, params: Option<P>) -> Request {
+    pub fn new_notify(method: String, params: Option<P>) -> Request<P> {
         Request {
-            method: method,
-            params: params.map(|p| p.to_json()),
+            method,
+            params,
             id: None
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl Request {
+    pub fn without_params<I: Into<Id>>(method: String, id: Option<I>) -> Request {
+        Request::new(method, None, id)
+    }
+
+    /// Deserializes `params` into `T`, e.g. a tuple for positional params or a struct for named ones.
+    ///
+    /// Missing params are treated as `null`, and so is an empty array that `T` does not accept, so
+    /// that `()` takes no params however they are sent. A shape mismatch is reported as
+    /// `InvalidParams` with the reason in its `data` member, so handlers can send it back as is.
+    pub fn parse_params<T: DeserializeOwned>(&self) -> ::std::result::Result<T, ProtocolError> {
+        let empty = matches!(self.params, Some(Value::Array(ref arr)) if arr.is_empty());
+        let params = self.params.clone().unwrap_or(Value::Null);
+        match serde_json::from_value(params) {
+            Err(err) if empty => serde_json::from_value(Value::Null).map_err(|_| err),
+            result => result,
+        }.map_err(|err| spec::errors::InvalidParams::with_detail(err.to_string()))
+    }
+}
+
+impl<P: Serialize> Serialize for Request<P> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        let len = 2 + self.params.is_some() as usize + self.id.is_some() as usize;
+        let mut state = serializer.serialize_struct("Request", len)?;
+        if let Some(ref id) = self.id {
+            state.serialize_field("id", id)?;
+        }
+        state.serialize_field("jsonrpc", "2.0")?;
+        state.serialize_field("method", &self.method)?;
+        if let Some(ref params) = self.params {
+            state.serialize_field("params", params)?;
+        }
+        state.end()
+    }
+}
+
+impl<'de, P: DeserializeOwned> Deserialize<'de> for Request<P> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Request<P>, D::Error> {
+        let obj = Map::deserialize(deserializer)?;
+        let req = spec::server::json_to_request(obj).map_err(de::Error::custom)?;
+        let params = match req.params {
+            Some(params) => Some(serde_json::from_value(params).map_err(de::Error::custom)?),
+            None => None,
+        };
+
+        Ok(Request {
+            method: req.method,
+            params,
+            id: req.id,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct ProtocolError {
     pub code: i64,
     pub message: String,
-    pub data: Option<Json>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl<'de> Deserialize<'de> for ProtocolError {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<ProtocolError, D::Error> {
+        let err = Value::deserialize(deserializer)?;
+        spec::client::json_to_error(err).map_err(de::Error::custom)
+    }
 }
 
-impl ToJson for ProtocolError {
-    fn to_json(&self) -> Json {
-        let mut obj = Object::new();
-        obj.insert("code".to_owned(), Json::I64(self.code));
-        obj.insert("message".to_owned(), Json::String(self.message.clone()));
-        if let Some(data) = self.data.clone() {
+impl From<ProtocolError> for Value {
+    fn from(err: ProtocolError) -> Value {
+        let mut obj = Map::new();
+        obj.insert("code".to_owned(), Value::from(err.code));
+        obj.insert("message".to_owned(), Value::String(err.message));
+        if let Some(data) = err.data {
             obj.insert("data".to_owned(), data);
         }
 
-        Json::Object(obj)
+        Value::Object(obj)
     }
 }
 
 impl ProtocolError {
-    pub fn new<D: ToJson>(code: i64, message: String, data: Option<D>) -> ProtocolError {
+    pub fn new<D: Into<Value>>(code: i64, message: String, data: Option<D>) -> ProtocolError {
         ProtocolError {
-            code: code,
-            message: message,
-            data: data.map(|d| d.to_json()),
+            code,
+            message,
+            data: data.map(|d| d.into()),
         }
     }
 }
 
+/// Reply to a request, carrying either its result or the error it failed with.
 #[derive(Debug, Clone, PartialEq)]
-pub struct Response {
-    pub result: Option<Json>,
-    pub error: Option<Json>,
-    pub id: Json,
+pub enum Response<R = Value> {
+    Success {
+        result: R,
+        id: Id,
+    },
+    Failure {
+        error: ProtocolError,
+        id: Id,
+    },
+}
+
+impl<R> Response<R> {
+    pub fn new<I: Into<Id>>(outcome: ::std::result::Result<R, ProtocolError>, id: I) -> Response<R> {
+        match outcome {
+            Ok(result) => Response::result(result, id),
+            Err(err) => Response::error(err, id),
+        }
+    }
+
+    pub fn result<I: Into<Id>>(result: R, id: I) -> Response<R> {
+        Response::Success {
+            result,
+            id: id.into(),
+        }
+    }
+
+    pub fn error<I: Into<Id>>(err: ProtocolError, id: I) -> Response<R> {
+        Response::Failure {
+            error: err,
+            id: id.into(),
+        }
+    }
+
+    pub fn id(&self) -> &Id {
+        match self {
+            Response::Success { id, .. } | Response::Failure { id, .. } => id,
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        match self {
+            Response::Success { .. } => true,
+            Response::Failure { .. } => false,
+        }
+    }
+
+    /// Converts into a `Result`, so a failed call can be propagated with `?`.
+    pub fn into_result(self) -> ::std::result::Result<R, ProtocolError> {
+        match self {
+            Response::Success { result, .. } => Ok(result),
+            Response::Failure { error, .. } => Err(error),
+        }
+    }
 }
 
 impl Response {
-    pub fn new<R: ToJson, E: ToJson, I: ToJson>(result: Option<R>, error: Option<E>, id: I) -> Response {
-        Response {
-            result: result.map(|r| r.to_json()),
-            error: error.map(|e| e.to_json()),
-            id: id.to_json(),
+    /// Deserializes the result into `R`, returning `None` if the response is a failure.
+    pub fn parse_result<R: DeserializeOwned>(&self) -> Result<Option<R>> {
+        match self {
+            Response::Success { result, .. } => {
+                serde_json::from_value(result.clone())
+                    .map(Some)
+                    .map_err(Error::ParserError)
+            },
+            Response::Failure { .. } => Ok(None),
         }
     }
+}
 
-    pub fn result<R: ToJson, I: ToJson>(result: R, id: I) -> Response {
-        Response::new(Some(result), None::<Json>, id)
+impl<R: Serialize> Serialize for Response<R> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Response", 3)?;
+        match self {
+            Response::Success { result, id } => {
+                state.serialize_field("id", id)?;
+                state.serialize_field("jsonrpc", "2.0")?;
+                state.serialize_field("result", result)?;
+            },
+            Response::Failure { error, id } => {
+                state.serialize_field("error", error)?;
+                state.serialize_field("id", id)?;
+                state.serialize_field("jsonrpc", "2.0")?;
+            }
+        }
+        state.end()
     }
+}
 
-    pub fn error<E: ToJson, I: ToJson>(err: E, id: I) -> Response {
-        Response::new(None::<Json>, Some(err), id)
+impl<'de, R: DeserializeOwned> Deserialize<'de> for Response<R> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Response<R>, D::Error> {
+        let obj = Map::deserialize(deserializer)?;
+        match spec::client::json_to_response(obj).map_err(de::Error::custom)? {
+            Response::Success { result, id } => {
+                let result = serde_json::from_value(result).map_err(de::Error::custom)?;
+                Ok(Response::Success { result, id })
+            },
+            Response::Failure { error, id } => Ok(Response::Failure { error, id }),
+        }
     }
 }
 
@@ -123,9 +347,9 @@ pub struct InternalError {
 impl InternalError {
     pub fn new(kind: InternalErrorKind, desc: &'static str, detail: Option<String>) -> InternalError {
         InternalError {
-            kind: kind,
-            desc: desc,
-            detail: detail,
+            kind,
+            desc,
+            detail,
         }
     }
 
@@ -142,43 +366,63 @@ impl InternalError {
     }
 }
 
+impl fmt::Display for InternalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail {
+            Some(ref detail) => write!(f, "{}: {}", self.desc, detail),
+            None => write!(f, "{}", self.desc),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum InternalErrorKind {
     InvalidVersion,
     InvalidResponse,
     MethodNotFound,
     InvalidRequest,
+    InvalidFrame,
+    /// An invalid frame whose end could not be found, so that nothing after it can be read
+    LostSync,
 }
 
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
-    EncoderError(EncoderError),
-    ParserError(ParserError),
+    EncoderError(serde_json::Error),
+    ParserError(serde_json::Error),
     ProtocolError(ProtocolError),
     InternalError(InternalError),
     NotUtf8,
+    /// No response arrived for the request `Id` before its deadline
+    Timeout(Id),
 }
 
 impl Error {
+    /// Whether a read failing with this error left the stream at the start of the next message.
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Error::IoError(..) => false,
+            Error::InternalError(err) => !matches!(err.kind(), InternalErrorKind::LostSync),
+            _ => true,
+        }
+    }
+
     pub fn to_protocol_error(&self) -> ProtocolError {
-        use proto::spec::errors;
+        use crate::proto::spec::errors;
 
         match self {
-            &Error::IoError(ref err) => {
-                errors::ServerError::with_detail(-32000,
-                    Some(Json::String(<io::Error as ::std::error::Error>::description(&err).to_owned())))
+            Error::IoError(err) => {
+                errors::ServerError::with_detail(-32000, Value::String(err.to_string()))
             },
-            &Error::EncoderError(ref err) => {
-                errors::ServerError::with_detail(-32001,
-                    Some(Json::String(<EncoderError as ::std::error::Error>::description(&err).to_owned())))
+            Error::EncoderError(err) => {
+                errors::ServerError::with_detail(-32001, Value::String(err.to_string()))
             },
-            &Error::ParserError(ref err) => {
-                let detail = Some(Json::String(<ParserError as ::std::error::Error>::description(&err).to_owned()));
-                errors::ParseError::with_detail(detail)
+            Error::ParserError(err) => {
+                errors::ParseError::with_detail(Value::String(err.to_string()))
             },
-            &Error::ProtocolError(ref err) => err.clone(),
-            &Error::InternalError(ref err) => {
+            Error::ProtocolError(err) => err.clone(),
+            Error::InternalError(err) => {
                 match err.kind() {
                     InternalErrorKind::InvalidVersion
                         | InternalErrorKind::InvalidRequest => {
@@ -189,10 +433,31 @@ impl Error {
                     },
                     InternalErrorKind::MethodNotFound => {
                         errors::MethodNotFound::with_detail(err.detail().map(|s| s.to_owned()))
+                    },
+                    InternalErrorKind::InvalidFrame
+                        | InternalErrorKind::LostSync => {
+                        errors::ParseError::with_detail(err.detail().map(|s| s.to_owned()))
                     }
                 }
             },
-            &Error::NotUtf8 => errors::InvalidRequest::new()
+            Error::NotUtf8 => errors::InvalidRequest::new(),
+            Error::Timeout(id) => {
+                errors::ServerError::with_detail(-32000, Value::String(format!("request {} timed out", id)))
+            },
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(err) => write!(f, "I/O error: {}", err),
+            Error::EncoderError(err) => write!(f, "encoder error: {}", err),
+            Error::ParserError(err) => write!(f, "parser error: {}", err),
+            Error::ProtocolError(err) => write!(f, "protocol error {}: {}", err.code, err.message),
+            Error::InternalError(err) => write!(f, "{}", err),
+            Error::NotUtf8 => write!(f, "stream did not contain valid UTF-8"),
+            Error::Timeout(id) => write!(f, "request {} timed out", id),
         }
     }
 }
@@ -205,23 +470,8 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<EncoderError> for Error {
-    fn from(err: EncoderError) -> Error {
-        Error::EncoderError(err)
-    }
-}
-
-impl From<ParserError> for Error {
-    fn from(err: ParserError) -> Error {
-        Error::ParserError(err)
-    }
-}
-
-impl From<io::CharsError> for Error {
-    fn from(err: io::CharsError) -> Error {
-        match err {
-            io::CharsError::Other(err) => Error::IoError(err),
-            io::CharsError::NotUtf8 => Error::NotUtf8,
-        }
+impl From<ProtocolError> for Error {
+    fn from(err: ProtocolError) -> Error {
+        Error::ProtocolError(err)
     }
 }
@@ -21,133 +21,182 @@
 //  DEALINGS IN THE SOFTWARE.
 
 use std::io::{Read, Write};
-use std::convert::From;
 
-use rustc_serialize::Encodable;
-use rustc_serialize::json::{self, Object, Array, Json, Encoder};
+use serde_json::{self, Map, Value};
 
-use proto::{self, Request, Response};
-use proto::{InternalErrorKind, InternalError};
-use proto::trans::{ServerResponse, SendRequest, GetResponse};
+use crate::proto::{self, ProtocolError, Request, Response};
+use crate::proto::{InternalErrorKind, InternalError};
+use crate::proto::trans::{ServerResponse, SendRequest, GetResponse};
 
-use proto::spec::check_version;
+use crate::proto::framing::{read_resumable, Concatenated, Framing};
+use crate::proto::spec::{check_version, json_to_id, write_message};
 
-pub struct ClientStream<'a, S: Read + Write + 'a> {
-    stream: &'a mut S,
+/// Client end of a stream, see `ClientReader` for reading with a timeout.
+pub struct ClientStream<S: Read + Write, F: Framing = Concatenated> {
+    stream: S,
+    framing: F,
+    partial: Vec<u8>,
 }
 
-pub struct ClientReader<'a, R: Read + 'a> {
-    reader: &'a mut R,
+/// Reading half of a client stream.
+///
+/// The underlying reader may time out, as with `TcpStream::set_read_timeout`. The part of a
+/// response read by then is kept, and reading resumes with it on the next `get_response`.
+pub struct ClientReader<R: Read, F: Framing = Concatenated> {
+    reader: R,
+    framing: F,
+    partial: Vec<u8>,
 }
 
-pub struct ClientWriter<'a, W: Write + 'a> {
-    writer: &'a mut W
+pub struct ClientWriter<W: Write, F: Framing = Concatenated> {
+    writer: W,
+    framing: F,
 }
 
-impl<'a, S: Read + Write + 'a> ClientStream<'a, S> {
-    pub fn new(stream: &'a mut S) -> ClientStream<'a, S> {
+impl<S: Read + Write> ClientStream<S> {
+    pub fn new(stream: S) -> ClientStream<S> {
+        ClientStream::with_framing(stream, Concatenated)
+    }
+}
+
+impl<S: Read + Write, F: Framing> ClientStream<S, F> {
+    pub fn with_framing(stream: S, framing: F) -> ClientStream<S, F> {
         ClientStream {
-            stream: stream,
+            stream,
+            framing,
+            partial: Vec::new(),
         }
     }
+
+    pub fn framing(&self) -> &F {
+        &self.framing
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
 }
 
-impl<'a, R: Read + 'a> ClientReader<'a, R> {
-    pub fn new(reader: &'a mut R) -> ClientReader<'a, R> {
-        ClientReader {
-            reader: reader,
-        }
+impl<R: Read> ClientReader<R> {
+    pub fn new(reader: R) -> ClientReader<R> {
+        ClientReader::with_framing(reader, Concatenated)
     }
 }
 
-impl<'a, W: Write + 'a> ClientWriter<'a, W> {
-    pub fn new(writer: &'a mut W) -> ClientWriter<'a, W> {
-        ClientWriter {
-            writer: writer,
+impl<R: Read, F: Framing> ClientReader<R, F> {
+    pub fn with_framing(reader: R, framing: F) -> ClientReader<R, F> {
+        ClientReader {
+            reader,
+            framing,
+            partial: Vec::new(),
         }
     }
-}
 
-impl<'a, W: Write + 'a> SendRequest for ClientWriter<'a, W> {
-    fn request(&mut self, request: Request) -> proto::Result<()> {
-        let obj = request_to_json(request);
+    pub fn framing(&self) -> &F {
+        &self.framing
+    }
 
-        {
-            let mut encoder = Encoder::new(&mut self.writer);
-            try!(obj.encode(&mut encoder));
-        }
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
 
-        self.writer.write_all(b"\r\n")
-            .and(self.writer.flush())
-            .map_err(From::from)
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
     }
 
-    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
-        let arr: Array = requests.into_iter().map(request_to_json).collect();
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<W: Write> ClientWriter<W> {
+    pub fn new(writer: W) -> ClientWriter<W> {
+        ClientWriter::with_framing(writer, Concatenated)
+    }
+}
 
-        {
-            let mut encoder = Encoder::new(&mut self.writer);
-            try!(arr.encode(&mut encoder));
+impl<W: Write, F: Framing> ClientWriter<W, F> {
+    pub fn with_framing(writer: W, framing: F) -> ClientWriter<W, F> {
+        ClientWriter {
+            writer,
+            framing,
         }
+    }
 
-        self.writer.write_all(b"\r\n")
-            .and(self.writer.flush())
-            .map_err(From::from)
+    pub fn framing(&self) -> &F {
+        &self.framing
     }
-}
 
-impl<'a, R: Read + 'a> GetResponse for ClientReader<'a, R> {
-    fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
-        let response = match Json::from_reader(&mut self.reader) {
-            Some(resp) => try!(resp),
-            None => return Ok(None),
-        };
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
 
-        response_from_json(response).map(|r| Some(r))
+    pub fn into_inner(self) -> W {
+        self.writer
     }
 }
 
-impl<'a, S: Read + Write + 'a> SendRequest for ClientStream<'a, S> {
+impl<W: Write, F: Framing> SendRequest for ClientWriter<W, F> {
     fn request(&mut self, request: Request) -> proto::Result<()> {
-        ClientWriter::new(&mut self.stream).request(request)
+        write_message(&mut self.writer, &self.framing, &request)
     }
 
     fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
-        ClientWriter::new(&mut self.stream).batch_request(requests)
+        write_message(&mut self.writer, &self.framing, &requests)
     }
 }
 
-impl<'a, S: Read + Write + 'a> GetResponse for ClientStream<'a, S> {
+impl<R: Read, F: Framing> GetResponse for ClientReader<R, F> {
     fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
-        ClientReader::new(&mut self.stream).get_response()
+        match read_resumable(&self.framing, &mut self.reader, &mut self.partial)? {
+            Some(resp) => response_from_json(resp).map(Some),
+            None => Ok(None),
+        }
     }
 }
 
-fn request_to_json(request: Request) -> Json {
-    let mut obj = Object::new();
-    obj.insert("jsonrpc".to_owned(), Json::String("2.0".to_owned()));
-    obj.insert("method".to_owned(), Json::String(request.method));
-    if let Some(params) = request.params {
-        obj.insert("params".to_owned(), params);
+impl<S: Read + Write, F: Framing> SendRequest for ClientStream<S, F> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        write_message(&mut self.stream, &self.framing, &request)
     }
-    if let Some(id) = request.id {
-        obj.insert("id".to_owned(), id);
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        write_message(&mut self.stream, &self.framing, &requests)
     }
+}
 
-    Json::Object(obj)
+impl<S: Read + Write, F: Framing> GetResponse for ClientStream<S, F> {
+    fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
+        match read_resumable(&self.framing, &mut self.stream, &mut self.partial)? {
+            Some(resp) => response_from_json(resp).map(Some),
+            None => Ok(None),
+        }
+    }
 }
 
-fn response_from_json(resp: Json) -> proto::Result<ServerResponse> {
+pub(crate) fn response_from_json(resp: Value) -> proto::Result<ServerResponse> {
     match resp {
-        Json::Object(obj) => {
+        Value::Object(obj) => {
             json_to_response(obj).map(ServerResponse::Single)
         },
-        Json::Array(arr) => {
+        Value::Array(arr) => {
             let mut batch = Vec::with_capacity(arr.len());
             for obj in arr.into_iter() {
                 match obj {
-                    Json::Object(obj) =>
-                        batch.push(try!(json_to_response(obj))),
+                    Value::Object(obj) =>
+                        batch.push(json_to_response(obj)?),
                     _ => {
                         let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
                                                       "Invalid JSON-RPC response",
@@ -169,14 +218,14 @@ fn response_from_json(resp: Json) -> proto::Result<ServerResponse> {
     }
 }
 
-fn json_to_response(mut obj: json::Object) -> proto::Result<Response> {
-    try!(check_version(&obj));
+pub(crate) fn json_to_response(mut obj: Map<String, Value>) -> proto::Result<Response> {
+    check_version(&obj)?;
 
     let result = obj.remove("result");
     let error = obj.remove("error");
 
     let id = match obj.remove("id") {
-        Some(id) => id,
+        Some(id) => json_to_id(id)?,
         None => {
             let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
                                           "Invalid JSON-RPC response",
@@ -185,5 +234,68 @@ fn json_to_response(mut obj: json::Object) -> proto::Result<Response> {
         }
     };
 
-    Ok(Response::new(result, error, id))
+    match (result, error) {
+        (Some(result), None) => Ok(Response::result(result, id)),
+        (None, Some(error)) => Ok(Response::error(json_to_error(error)?, id)),
+        (Some(..), Some(..)) => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "Invalid JSON-RPC response",
+                                          Some("`result` and `error` must not both exist".to_owned()));
+            Err(proto::Error::InternalError(ierr))
+        },
+        (None, None) => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "Invalid JSON-RPC response",
+                                          Some("Either `result` or `error` is required".to_owned()));
+            Err(proto::Error::InternalError(ierr))
+        }
+    }
+}
+
+pub(crate) fn json_to_error(err: Value) -> proto::Result<ProtocolError> {
+    let mut obj = match err {
+        Value::Object(obj) => obj,
+        _ => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "Invalid JSON-RPC error",
+                                          Some(format!("Expecting an Error object, but found {:?}", err)));
+            return Err(proto::Error::InternalError(ierr));
+        }
+    };
+
+    let code = match obj.remove("code") {
+        Some(Value::Number(ref n)) if n.is_i64() => n.as_i64().unwrap(),
+        Some(code) => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "`code` must be an Integer",
+                                          Some(format!("Expecting error code, but found {:?}", code)));
+            return Err(proto::Error::InternalError(ierr));
+        },
+        None => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "`code` is required",
+                                          None);
+            return Err(proto::Error::InternalError(ierr));
+        }
+    };
+
+    let message = match obj.remove("message") {
+        Some(Value::String(m)) => m,
+        Some(message) => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "`message` must be a String",
+                                          Some(format!("Expecting error message, but found {:?}", message)));
+            return Err(proto::Error::InternalError(ierr));
+        },
+        None => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
+                                          "`message` is required",
+                                          None);
+            return Err(proto::Error::InternalError(ierr));
+        }
+    };
+
+    let data = obj.remove("data");
+
+    Ok(ProtocolError::new(code, message, data))
 }
@@ -28,120 +28,120 @@ pub const ERRCODE_INTERNAL_ERROR: i64 = -32603;
 
 #[allow(non_snake_case)]
 pub mod ParseError {
-    use rustc_serialize::json::{ToJson, Json};
-    use proto::ProtocolError;
+    use serde_json::Value;
+    use crate::proto::ProtocolError;
 
     use super::*;
 
-    fn create<D: ToJson>(detail: Option<D>) -> ProtocolError {
+    fn create<D: Into<Value>>(detail: Option<D>) -> ProtocolError {
         ProtocolError::new(ERRCODE_PARSE_ERROR, "Parse error".to_owned(), detail)
     }
 
     pub fn new() -> ProtocolError {
-        create(None::<Json>)
+        create(None::<Value>)
     }
 
-    pub fn with_detail<D: ToJson>(detail: D) -> ProtocolError {
+    pub fn with_detail<D: Into<Value>>(detail: D) -> ProtocolError {
         create(Some(detail))
     }
 }
 
 #[allow(non_snake_case)]
 pub mod InvalidRequest {
-    use rustc_serialize::json::{ToJson, Json};
-    use proto::ProtocolError;
+    use serde_json::Value;
+    use crate::proto::ProtocolError;
 
     use super::*;
 
-    fn create<D: ToJson>(detail: Option<D>) -> ProtocolError {
+    fn create<D: Into<Value>>(detail: Option<D>) -> ProtocolError {
         ProtocolError::new(ERRCODE_INVALID_REQUEST, "Invalid Request".to_owned(), detail)
     }
 
     pub fn new() -> ProtocolError {
-        create(None::<Json>)
+        create(None::<Value>)
     }
 
-    pub fn with_detail<D: ToJson>(detail: D) -> ProtocolError {
+    pub fn with_detail<D: Into<Value>>(detail: D) -> ProtocolError {
         create(Some(detail))
     }
 }
 
 #[allow(non_snake_case)]
 pub mod MethodNotFound {
-    use rustc_serialize::json::{ToJson, Json};
-    use proto::ProtocolError;
+    use serde_json::Value;
+    use crate::proto::ProtocolError;
 
     use super::*;
 
-    fn create<D: ToJson>(detail: Option<D>) -> ProtocolError {
+    fn create<D: Into<Value>>(detail: Option<D>) -> ProtocolError {
         ProtocolError::new(ERRCODE_METHOD_NOT_FOUND, "Method not found".to_owned(), detail)
     }
 
     pub fn new() -> ProtocolError {
-        create(None::<Json>)
+        create(None::<Value>)
     }
 
-    pub fn with_detail<D: ToJson>(detail: D) -> ProtocolError {
+    pub fn with_detail<D: Into<Value>>(detail: D) -> ProtocolError {
         create(Some(detail))
     }
 }
 
 #[allow(non_snake_case)]
 pub mod InvalidParams {
-    use rustc_serialize::json::{ToJson, Json};
-    use proto::ProtocolError;
+    use serde_json::Value;
+    use crate::proto::ProtocolError;
 
     use super::*;
 
-    fn create<D: ToJson>(detail: Option<D>) -> ProtocolError {
+    fn create<D: Into<Value>>(detail: Option<D>) -> ProtocolError {
         ProtocolError::new(ERRCODE_INVALID_PARAMS, "Invalid params".to_owned(), detail)
     }
 
     pub fn new() -> ProtocolError {
-        create(None::<Json>)
+        create(None::<Value>)
     }
 
-    pub fn with_detail<D: ToJson>(detail: D) -> ProtocolError {
+    pub fn with_detail<D: Into<Value>>(detail: D) -> ProtocolError {
         create(Some(detail))
     }
 }
 
 #[allow(non_snake_case)]
 pub mod InternalError {
-    use rustc_serialize::json::{ToJson, Json};
-    use proto::ProtocolError;
+    use serde_json::Value;
+    use crate::proto::ProtocolError;
 
     use super::*;
 
-    pub fn create<D: ToJson>(detail: Option<D>) -> ProtocolError {
+    pub fn create<D: Into<Value>>(detail: Option<D>) -> ProtocolError {
         ProtocolError::new(ERRCODE_INTERNAL_ERROR, "Internal error".to_owned(), detail)
     }
 
     pub fn new() -> ProtocolError {
-        create(None::<Json>)
+        create(None::<Value>)
     }
 
-    pub fn with_detail<D: ToJson>(detail: D) -> ProtocolError {
+    pub fn with_detail<D: Into<Value>>(detail: D) -> ProtocolError {
         create(Some(detail))
     }
 }
 
 #[allow(non_snake_case)]
 pub mod ServerError {
-    use rustc_serialize::json::{ToJson, Json};
-    use proto::ProtocolError;
+    use serde_json::Value;
+    use crate::proto::ProtocolError;
 
-    pub fn create<D: ToJson>(code: i64, detail: Option<D>) -> ProtocolError {
-        assert!(code <= -32000 && code >= -32099, "ServerError code must be in [-32099, -32000]");
+    pub fn create<D: Into<Value>>(code: i64, detail: Option<D>) -> ProtocolError {
+        assert!((-32099..=-32000).contains(&code), "ServerError code must be in [-32099, -32000]");
 
         ProtocolError::new(code, "Server error".to_owned(), detail)
     }
 
     pub fn new(code: i64) -> ProtocolError {
-        create(code, None::<Json>)
+        create(code, None::<Value>)
     }
 
-    pub fn with_detail<D: ToJson>(code: i64, detail: D) -> ProtocolError {
+    pub fn with_detail<D: Into<Value>>(code: i64, detail: D) -> ProtocolError {
         create(code, Some(detail))
     }
 }
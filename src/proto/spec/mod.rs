@@ -22,16 +22,23 @@
 
 pub use self::client::{ClientReader, ClientWriter, ClientStream};
 pub use self::server::{ServerReader, ServerWriter, ServerStream};
+pub use self::peer::{PeerReader, PeerWriter};
 
-use rustc_serialize::json::{self, Object, Json};
+use std::io::Write;
 
-use proto::{self, InternalErrorKind, InternalError};
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+
+use crate::proto::{self, Id, InternalErrorKind, InternalError};
+use crate::proto::framing::Framing;
+use crate::proto::trans::Message;
 
 pub mod client;
 pub mod server;
+pub mod peer;
 pub mod errors;
 
-pub fn check_version(obj: &json::Object) -> proto::Result<()> {
+pub fn check_version(obj: &Map<String, Value>) -> proto::Result<()> {
     match obj.get("jsonrpc") {
         None => {
             let ierr = InternalError::new(InternalErrorKind::InvalidVersion,
@@ -39,7 +46,7 @@ pub fn check_version(obj: &json::Object) -> proto::Result<()> {
                                           Some("Missing `jsonrpc` field".to_owned()));
             Err(proto::Error::InternalError(ierr))
         },
-        Some(&Json::String(ref ver)) => {
+        Some(Value::String(ver)) => {
             match &ver[..] {
                 "2.0" => Ok(()),
                 _ => {
@@ -59,26 +66,75 @@ pub fn check_version(obj: &json::Object) -> proto::Result<()> {
     }
 }
 
+/// Converts the `id` member of a message, rejecting anything but an integer, a string or `null`.
+pub fn json_to_id(id: Value) -> proto::Result<Id> {
+    match id {
+        Value::Null => Ok(Id::Null),
+        Value::String(s) => Ok(Id::String(s)),
+        Value::Number(ref n) if n.is_i64() => Ok(Id::Number(n.as_i64().unwrap())),
+        _ => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidRequest,
+                                          "Invalid JSON-RPC id",
+                                          Some(format!("Expecting an integer, a string or null, but found {}", id)));
+            Err(proto::Error::InternalError(ierr))
+        }
+    }
+}
+
+/// Tells a request from a response by the `method` member, which only requests have.
+///
+/// A batch is taken after its first element.
+pub fn message_from_json(msg: Value) -> proto::Result<Message> {
+    let is_request = match msg {
+        Value::Object(ref obj) => obj.contains_key("method"),
+        Value::Array(ref arr) => {
+            match arr.first() {
+                Some(Value::Object(obj)) => obj.contains_key("method"),
+                _ => true,
+            }
+        },
+        _ => true,
+    };
+
+    if is_request {
+        server::request_from_json(msg).map(Message::Request)
+    } else {
+        client::response_from_json(msg).map(Message::Response)
+    }
+}
+
+/// Encodes `message` and writes it as one frame, flushing the writer.
+fn write_message<W, F, T>(writer: &mut W, framing: &F, message: &T) -> proto::Result<()>
+    where W: Write,
+          F: Framing,
+          T: Serialize
+{
+    let payload = serde_json::to_vec(message).map_err(proto::Error::EncoderError)?;
+    framing.write_message(writer, &payload)
+        .and(writer.flush())
+        .map_err(From::from)
+}
+
 #[cfg(test)]
 mod test {
     use std::io::{Cursor, Write, Seek, SeekFrom};
 
-    use proto::{Request, Response};
-    use proto::trans::{ClientRequest, ServerResponse, SendRequest, GetRequest, GetResponse, SendResponse};
+    use crate::proto::{self, Id, InternalErrorKind, ProtocolError, Request, Response};
+    use crate::proto::trans::{ClientRequest, ServerResponse, SendRequest, GetRequest, GetResponse, SendResponse};
 
-    use rustc_serialize::json::{Array, Json};
+    use serde_json::Value;
 
-    use super::{ClientWriter, ClientReader, ServerWriter, ServerReader};
+    use super::{errors, ClientWriter, ClientReader, ServerWriter, ServerReader};
 
     #[test]
     fn test_spec20_request() {
-        let params: Array = vec![
-            Json::String("ping".to_owned()),
+        let params = vec![
+            Value::String("ping".to_owned()),
         ];
 
         let request = Request::new("echo".to_owned(),
-                                   Some(Json::Array(params)),
-                                   Json::U64(1));
+                                   Some(Value::Array(params)),
+                                   Some(1));
 
         let mut buf = Cursor::new(vec![]);
 
@@ -102,9 +158,9 @@ mod test {
 
     #[test]
     fn test_spec20_server_response() {
-        let result: Json = Json::String("pong".to_owned());
+        let result = Value::String("pong".to_owned());
 
-        let response = Response::new(Some(result), None::<Json>, Json::U64(1));
+        let response = Response::result(result, 1);
 
         let mut buf = Cursor::new(vec![]);
 
@@ -124,4 +180,133 @@ mod test {
 
         assert_eq!(ServerResponse::Single(response), response_cli);
     }
+
+    #[test]
+    fn test_spec20_empty_batch_response() {
+        let mut buf = Cursor::new(vec![]);
+
+        {
+            // A batch of notifications has nothing to answer
+            let mut server = ServerWriter::new(&mut buf);
+            server.batch_response(vec![]).unwrap();
+            server.server_response(ServerResponse::Batch(vec![])).unwrap();
+        }
+
+        assert!(buf.get_ref().is_empty());
+    }
+
+    #[test]
+    fn test_spec20_serde_batch() {
+        let requests = vec![
+            Request::new("echo".to_owned(), Some(Value::from(vec!["ping"])), Some(1)),
+            Request::new_notify("touch".to_owned(), None::<Value>),
+        ];
+
+        let batch = ClientRequest::Batch(requests.into_iter().map(Ok).collect());
+        let encoded = serde_json::to_string(&batch).unwrap();
+        assert_eq!(encoded,
+                   "[{\"id\":1,\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"params\":[\"ping\"]},\
+                    {\"jsonrpc\":\"2.0\",\"method\":\"touch\"}]");
+
+        let decoded: ClientRequest = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(batch, decoded);
+
+        assert!(serde_json::from_str::<Request>("{\"jsonrpc\":\"1.0\",\"method\":\"echo\"}").is_err());
+    }
+
+    #[test]
+    fn test_spec20_typed_params() {
+        let encoded = "{\"id\":1,\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2]}";
+
+        let typed: Request<(i64, i64)> = serde_json::from_str(encoded).unwrap();
+        assert_eq!(Some((1, 2)), typed.params);
+
+        let request: Request = serde_json::from_str(encoded).unwrap();
+        assert_eq!((1, 2), request.parse_params::<(i64, i64)>().unwrap());
+
+        let err = request.parse_params::<(String, String)>().unwrap_err();
+        assert_eq!(errors::ERRCODE_INVALID_PARAMS, err.code);
+        assert!(err.data.is_some());
+    }
+
+    #[test]
+    fn test_spec20_id() {
+        let request: Request = serde_json::from_str("{\"id\":\"abc\",\"jsonrpc\":\"2.0\",\"method\":\"echo\"}").unwrap();
+        assert_eq!(Some(Id::String("abc".to_owned())), request.id);
+
+        let request: Request = serde_json::from_str("{\"id\":null,\"jsonrpc\":\"2.0\",\"method\":\"echo\"}").unwrap();
+        assert_eq!(Some(Id::Null), request.id);
+
+        let notify: Request = serde_json::from_str("{\"jsonrpc\":\"2.0\",\"method\":\"echo\"}").unwrap();
+        assert_eq!(None, notify.id);
+
+        for id in &["1.5", "{}", "[1]", "true"] {
+            let obj = serde_json::from_str(&format!("{{\"id\":{},\"jsonrpc\":\"2.0\",\"method\":\"echo\"}}", id)).unwrap();
+            match super::server::json_to_request(obj) {
+                Err(proto::Error::InternalError(ref err)) => {
+                    assert!(matches!(err.kind(), InternalErrorKind::InvalidRequest));
+                },
+                other => panic!("Expecting InvalidRequest for id {}, but found {:?}", id, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_spec20_response_outcome() {
+        let failure: Response = serde_json::from_str(
+            "{\"error\":{\"code\":-32601,\"message\":\"Method not found\"},\"id\":1,\"jsonrpc\":\"2.0\"}").unwrap();
+        assert_eq!(&Id::Number(1), failure.id());
+        assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, failure.into_result().unwrap_err().code);
+
+        let success: Response<String> = serde_json::from_str("{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"pong\"}").unwrap();
+        assert_eq!("pong", success.into_result().unwrap());
+
+        for resp in &["{\"id\":1,\"jsonrpc\":\"2.0\"}",
+                      "{\"error\":{\"code\":1,\"message\":\"\"},\"id\":1,\"jsonrpc\":\"2.0\",\"result\":1}"] {
+            let obj = serde_json::from_str(resp).unwrap();
+            match super::client::json_to_response(obj) {
+                Err(proto::Error::InternalError(ref err)) => {
+                    assert!(matches!(err.kind(), InternalErrorKind::InvalidResponse));
+                },
+                other => panic!("Expecting InvalidResponse for {}, but found {:?}", resp, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_spec20_error_decode() {
+        let err: ProtocolError = serde_json::from_str("{\"code\":-32000,\"message\":\"Server error\",\"data\":[1]}").unwrap();
+        assert_eq!(ProtocolError::new(-32000, "Server error".to_owned(), Some(Value::from(vec![1]))), err);
+
+        for err in &["\"oops\"", "{\"message\":\"m\"}", "{\"code\":1.5,\"message\":\"m\"}", "{\"code\":1,\"message\":2}"] {
+            match super::client::json_to_error(serde_json::from_str(err).unwrap()) {
+                Err(proto::Error::InternalError(ref ierr)) => {
+                    assert!(matches!(ierr.kind(), InternalErrorKind::InvalidResponse));
+                },
+                other => panic!("Expecting InvalidResponse for {}, but found {:?}", err, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_spec20_invalid_batch() {
+        let batch = super::server::request_from_json(serde_json::from_str("[1,{\"jsonrpc\":\"2.0\"},\
+            {\"jsonrpc\":\"2.0\",\"method\":\"echo\",\"id\":1}]").unwrap()).unwrap();
+        match batch {
+            ClientRequest::Batch(reqs) => {
+                assert_eq!(3, reqs.len());
+                assert_eq!(errors::ERRCODE_INVALID_REQUEST, reqs[0].as_ref().unwrap_err().code);
+                assert_eq!(errors::ERRCODE_INVALID_REQUEST, reqs[1].as_ref().unwrap_err().code);
+                assert_eq!("echo", reqs[2].as_ref().unwrap().method);
+            },
+            other => panic!("Expecting a batch, but found {:?}", other),
+        }
+
+        match super::server::request_from_json(Value::Array(vec![])) {
+            Err(proto::Error::InternalError(ref err)) => {
+                assert!(matches!(err.kind(), InternalErrorKind::InvalidRequest));
+            },
+            other => panic!("Expecting InvalidRequest for an empty batch, but found {:?}", other),
+        }
+    }
 }
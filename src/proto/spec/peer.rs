@@ -0,0 +1,135 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use std::io::{Read, Write};
+
+use crate::proto::{self, Request, Response};
+use crate::proto::trans::{GetMessage, Message, SendRequest, SendResponse};
+
+use crate::proto::framing::{Concatenated, Framing};
+use crate::proto::spec::{message_from_json, write_message};
+
+/// Reading half of a connection where both ends send requests.
+pub struct PeerReader<R: Read, F: Framing = Concatenated> {
+    reader: R,
+    framing: F,
+}
+
+/// Writing half of a connection where both ends send requests.
+pub struct PeerWriter<W: Write, F: Framing = Concatenated> {
+    writer: W,
+    framing: F,
+}
+
+impl<R: Read> PeerReader<R> {
+    pub fn new(reader: R) -> PeerReader<R> {
+        PeerReader::with_framing(reader, Concatenated)
+    }
+}
+
+impl<R: Read, F: Framing> PeerReader<R, F> {
+    pub fn with_framing(reader: R, framing: F) -> PeerReader<R, F> {
+        PeerReader {
+            reader,
+            framing,
+        }
+    }
+
+    pub fn framing(&self) -> &F {
+        &self.framing
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<W: Write> PeerWriter<W> {
+    pub fn new(writer: W) -> PeerWriter<W> {
+        PeerWriter::with_framing(writer, Concatenated)
+    }
+}
+
+impl<W: Write, F: Framing> PeerWriter<W, F> {
+    pub fn with_framing(writer: W, framing: F) -> PeerWriter<W, F> {
+        PeerWriter {
+            writer,
+            framing,
+        }
+    }
+
+    pub fn framing(&self) -> &F {
+        &self.framing
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<R: Read, F: Framing> GetMessage for PeerReader<R, F> {
+    fn get_message(&mut self) -> proto::Result<Option<Message>> {
+        match self.framing.read_message(&mut self.reader)? {
+            Some(msg) => message_from_json(msg).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<W: Write, F: Framing> SendRequest for PeerWriter<W, F> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        write_message(&mut self.writer, &self.framing, &request)
+    }
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        write_message(&mut self.writer, &self.framing, &requests)
+    }
+}
+
+impl<W: Write, F: Framing> SendResponse for PeerWriter<W, F> {
+    fn response(&mut self, response: Response) -> proto::Result<()> {
+        write_message(&mut self.writer, &self.framing, &response)
+    }
+
+    fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
+        if responses.is_empty() {
+            return Ok(());
+        }
+        write_message(&mut self.writer, &self.framing, &responses)
+    }
+}
@@ -21,129 +21,194 @@
 //  DEALINGS IN THE SOFTWARE.
 
 use std::io::{Read, Write};
-use std::convert::From;
 
-use rustc_serialize::Encodable;
-use rustc_serialize::json::{self, Object, Array, Json, Encoder};
+use serde_json::{self, Map, Value};
 
-use proto::{self, Request, Response};
-use proto::{InternalErrorKind, InternalError};
-use proto::trans::{ClientRequest, GetRequest, SendResponse};
+use crate::proto::{self, Request, Response};
+use crate::proto::{InternalErrorKind, InternalError};
+use crate::proto::trans::{ClientRequest, GetRequest, SendRequest, SendResponse};
 
-use proto::spec::check_version;
+use crate::proto::framing::{Concatenated, Framing};
+use crate::proto::spec::{check_version, errors, json_to_id, write_message};
 
-pub struct ServerStream<'a, S: Read + Write + 'a> {
-    stream: &'a mut S,
+pub struct ServerStream<S: Read + Write, F: Framing = Concatenated> {
+    stream: S,
+    framing: F,
 }
 
-pub struct ServerReader<'a, R: Read + 'a> {
-    reader: &'a mut R,
+pub struct ServerReader<R: Read, F: Framing = Concatenated> {
+    reader: R,
+    framing: F,
 }
 
-pub struct ServerWriter<'a, W: Write + 'a> {
-    writer: &'a mut W,
+pub struct ServerWriter<W: Write, F: Framing = Concatenated> {
+    writer: W,
+    framing: F,
 }
 
-impl<'a, S: Read + Write + 'a> ServerStream<'a, S> {
-    pub fn new(s: &'a mut S) -> ServerStream<'a, S> {
+impl<S: Read + Write> ServerStream<S> {
+    pub fn new(stream: S) -> ServerStream<S> {
+        ServerStream::with_framing(stream, Concatenated)
+    }
+}
+
+impl<S: Read + Write, F: Framing> ServerStream<S, F> {
+    pub fn with_framing(stream: S, framing: F) -> ServerStream<S, F> {
         ServerStream {
-            stream: s,
+            stream,
+            framing,
         }
     }
+
+    pub fn framing(&self) -> &F {
+        &self.framing
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
 }
 
-impl<'a, R: Read + 'a> ServerReader<'a, R> {
-    pub fn new(r: &'a mut R) -> ServerReader<'a, R> {
-        ServerReader {
-            reader: r,
-        }
+impl<R: Read> ServerReader<R> {
+    pub fn new(reader: R) -> ServerReader<R> {
+        ServerReader::with_framing(reader, Concatenated)
     }
 }
 
-impl<'a, W: Write + 'a> ServerWriter<'a, W> {
-    pub fn new(w: &'a mut W) -> ServerWriter<'a, W> {
-        ServerWriter {
-            writer: w,
+impl<R: Read, F: Framing> ServerReader<R, F> {
+    pub fn with_framing(reader: R, framing: F) -> ServerReader<R, F> {
+        ServerReader {
+            reader,
+            framing,
         }
     }
-}
 
-impl<'a, W: Write + 'a> SendResponse for ServerWriter<'a, W> {
-    fn response(&mut self, response: Response) -> proto::Result<()> {
-        let obj = response_to_json(response);
+    pub fn framing(&self) -> &F {
+        &self.framing
+    }
 
-        {
-            let mut encoder = Encoder::new(&mut self.writer);
-            try!(obj.encode(&mut encoder));
-        }
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
 
-        self.writer.write_all(b"\r\n")
-            .and(self.writer.flush())
-            .map_err(From::from)
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
     }
 
-    fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
-        let arr: Array = responses.into_iter().map(response_to_json).collect();
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
 
-        {
-            let mut encoder = Encoder::new(&mut self.writer);
-            try!(arr.encode(&mut encoder));
+impl<W: Write> ServerWriter<W> {
+    pub fn new(writer: W) -> ServerWriter<W> {
+        ServerWriter::with_framing(writer, Concatenated)
+    }
+}
+
+impl<W: Write, F: Framing> ServerWriter<W, F> {
+    pub fn with_framing(writer: W, framing: F) -> ServerWriter<W, F> {
+        ServerWriter {
+            writer,
+            framing,
         }
+    }
 
-        self.writer.write_all(b"\r\n")
-            .and(self.writer.flush())
-            .map_err(From::from)
+    pub fn framing(&self) -> &F {
+        &self.framing
     }
-}
 
-impl<'a, R: Read + 'a> GetRequest for ServerReader<'a, R> {
-    fn get_request(&mut self) -> proto::Result<Option<ClientRequest>> {
-        let request = match Json::from_reader(&mut self.reader) {
-            Some(req) => try!(req),
-            None => return Ok(None),
-        };
-        request_from_json(request).map(|r| Some(r))
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
     }
 }
 
-impl<'a, S: Read + Write + 'a> SendResponse for ServerStream<'a, S> {
+impl<W: Write, F: Framing> SendResponse for ServerWriter<W, F> {
     fn response(&mut self, response: Response) -> proto::Result<()> {
-        ServerWriter::new(&mut self.stream).response(response)
+        write_message(&mut self.writer, &self.framing, &response)
     }
 
     fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
-        ServerWriter::new(&mut self.stream).batch_response(responses)
+        if responses.is_empty() {
+            return Ok(());
+        }
+        write_message(&mut self.writer, &self.framing, &responses)
     }
 }
 
-impl<'a, S: Read + Write + 'a> GetRequest for ServerStream<'a, S> {
+/// Lets the server push notifications, such as the ones of a subscription.
+impl<W: Write, F: Framing> SendRequest for ServerWriter<W, F> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        write_message(&mut self.writer, &self.framing, &request)
+    }
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        write_message(&mut self.writer, &self.framing, &requests)
+    }
+}
+
+impl<R: Read, F: Framing> GetRequest for ServerReader<R, F> {
     fn get_request(&mut self) -> proto::Result<Option<ClientRequest>> {
-        ServerReader::new(&mut self.stream).get_request()
+        match self.framing.read_message(&mut self.reader)? {
+            Some(req) => request_from_json(req).map(Some),
+            None => Ok(None),
+        }
     }
 }
 
-fn response_to_json(resp: Response) -> Json {
-    let mut obj = json::Object::new();
-    obj.insert("jsonrpc".to_owned(), Json::String("2.0".to_owned()));
+impl<S: Read + Write, F: Framing> SendResponse for ServerStream<S, F> {
+    fn response(&mut self, response: Response) -> proto::Result<()> {
+        write_message(&mut self.stream, &self.framing, &response)
+    }
 
-    if let Some(result) = resp.result {
-        obj.insert("result".to_owned(), result);
+    fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
+        if responses.is_empty() {
+            return Ok(());
+        }
+        write_message(&mut self.stream, &self.framing, &responses)
     }
+}
 
-    if let Some(error) = resp.error {
-        obj.insert("error".to_owned(), error);
+impl<S: Read + Write, F: Framing> SendRequest for ServerStream<S, F> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        write_message(&mut self.stream, &self.framing, &request)
     }
 
-    obj.insert("id".to_owned(), resp.id);
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        write_message(&mut self.stream, &self.framing, &requests)
+    }
+}
 
-    Json::Object(obj)
+impl<S: Read + Write, F: Framing> GetRequest for ServerStream<S, F> {
+    fn get_request(&mut self) -> proto::Result<Option<ClientRequest>> {
+        match self.framing.read_message(&mut self.stream)? {
+            Some(req) => request_from_json(req).map(Some),
+            None => Ok(None),
+        }
+    }
 }
 
-fn json_to_request(mut obj: json::Object) -> proto::Result<Request> {
-    try!(check_version(&obj));
+pub(crate) fn json_to_request(mut obj: Map<String, Value>) -> proto::Result<Request> {
+    check_version(&obj)?;
 
     let method = match obj.remove("method") {
-        Some(Json::String(m)) => m,
+        Some(Value::String(m)) => m,
         Some(obj) => {
             let ierr = InternalError::new(InternalErrorKind::InvalidRequest,
                                           "`method` must be a String",
@@ -160,38 +225,42 @@ fn json_to_request(mut obj: json::Object) -> proto::Result<Request> {
 
     let params = obj.remove("params");
 
-    let id = obj.remove("id");
+    // A missing `id` marks a notification, while an explicit `null` is still a request
+    let id = match obj.remove("id") {
+        Some(id) => Some(json_to_id(id)?),
+        None => None,
+    };
 
     Ok(Request::new(method, params, id))
 }
 
-fn request_from_json(req: Json) -> proto::Result<ClientRequest> {
+pub(crate) fn request_from_json(req: Value) -> proto::Result<ClientRequest> {
     match req {
-        Json::Object(obj) => {
+        Value::Object(obj) => {
             json_to_request(obj).map(ClientRequest::Single)
         },
-        Json::Array(arr) => {
-            let mut batch = Vec::with_capacity(arr.len());
-            for obj in arr.into_iter() {
+        Value::Array(ref arr) if arr.is_empty() => {
+            let ierr = InternalError::new(InternalErrorKind::InvalidRequest,
+                                          "Invalid JSON-RPC request",
+                                          Some("Batch must contain at least one Request".to_owned()));
+            Err(proto::Error::InternalError(ierr))
+        },
+        Value::Array(arr) => {
+            // Every element is validated on its own, so that each invalid one gets its own error response
+            let batch = arr.into_iter().map(|obj| {
                 match obj {
-                    Json::Object(obj) =>
-                        batch.push(try!(json_to_request(obj))),
-                    _ => {
-                        let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
-                                                      "Invalid JSON-RPC response",
-                                                      Some(format!("Expecting a Response object, but found {:?}",
-                                                                   obj)));
-                        return Err(proto::Error::InternalError(ierr));
-                    }
+                    Value::Object(obj) => json_to_request(obj).map_err(|err| err.to_protocol_error()),
+                    _ => Err(errors::InvalidRequest::with_detail(
+                                format!("Expecting a Request object, but found {:?}", obj))),
                 }
-            }
+            }).collect();
 
             Ok(ClientRequest::Batch(batch))
         },
         _ => {
-            let ierr = InternalError::new(InternalErrorKind::InvalidResponse,
-                                          "Invalid JSON-RPC response",
-                                          Some(format!("Expecting JSON-RPC response, but found {:?}", req)));
+            let ierr = InternalError::new(InternalErrorKind::InvalidRequest,
+                                          "Invalid JSON-RPC request",
+                                          Some(format!("Expecting JSON-RPC request, but found {:?}", req)));
             Err(proto::Error::InternalError(ierr))
         }
     }
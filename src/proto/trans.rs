@@ -20,12 +20,19 @@
 //  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 //  DEALINGS IN THE SOFTWARE.
 
-use proto::{Request, Response, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{de, ser};
+use serde::ser::SerializeSeq;
+use serde_json::Value;
+
+use crate::proto::{ProtocolError, Request, Response, Result};
+use crate::proto::spec;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ClientRequest {
     Single(Request),
-    Batch(Vec<Request>),
+    /// Elements that are not valid Request objects are kept as the error to reply with.
+    Batch(Vec<::std::result::Result<Request, ProtocolError>>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -34,6 +41,72 @@ pub enum ServerResponse {
     Batch(Vec<Response>),
 }
 
+/// Any message, on a connection where both ends send requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Request(ClientRequest),
+    Response(ServerResponse),
+}
+
+impl Serialize for ClientRequest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match self {
+            ClientRequest::Single(req) => req.serialize(serializer),
+            ClientRequest::Batch(reqs) => {
+                let mut seq = serializer.serialize_seq(Some(reqs.len()))?;
+                for req in reqs {
+                    match req {
+                        Ok(req) => seq.serialize_element(req)?,
+                        Err(err) => {
+                            return Err(ser::Error::custom(format!("invalid batch element: {}", err.message)));
+                        }
+                    }
+                }
+                seq.end()
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientRequest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<ClientRequest, D::Error> {
+        let req = Value::deserialize(deserializer)?;
+        spec::server::request_from_json(req).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ServerResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match self {
+            ServerResponse::Single(resp) => resp.serialize(serializer),
+            ServerResponse::Batch(resps) => resps.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<ServerResponse, D::Error> {
+        let resp = Value::deserialize(deserializer)?;
+        spec::client::response_from_json(resp).map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for Message {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        match self {
+            Message::Request(req) => req.serialize(serializer),
+            Message::Response(resp) => resp.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Message {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Message, D::Error> {
+        let msg = Value::deserialize(deserializer)?;
+        spec::message_from_json(msg).map_err(de::Error::custom)
+    }
+}
+
 pub trait SendRequest {
     fn request(&mut self, request: Request) -> Result<()>;
     fn batch_request(&mut self, requests: Vec<Request>) -> Result<()>;
@@ -45,9 +118,22 @@ pub trait GetResponse {
 
 pub trait SendResponse {
     fn response(&mut self, response: Response) -> Result<()>;
+    /// Writes nothing for an empty batch, as a batch of notifications must not be answered.
     fn batch_response(&mut self, responses: Vec<Response>) -> Result<()>;
+
+    fn server_response(&mut self, response: ServerResponse) -> Result<()> {
+        match response {
+            ServerResponse::Single(resp) => self.response(resp),
+            ServerResponse::Batch(resps) => self.batch_response(resps),
+        }
+    }
 }
 
 pub trait GetRequest {
     fn get_request(&mut self) -> Result<Option<ClientRequest>>;
 }
+
+/// Reads both requests and responses, for a connection where either end may call the other.
+pub trait GetMessage {
+    fn get_message(&mut self) -> Result<Option<Message>>;
+}
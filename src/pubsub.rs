@@ -0,0 +1,426 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Subscriptions, where the server pushes notifications tagged with a subscription id.
+//!
+//! Every notification of a subscription is sent as
+//! `{"method": <method>, "params": {"subscription": <id>, "result": <value>}}`, the way Ethereum
+//! nodes do. The server side tracks the subscriptions of one connection with a
+//! `SubscriptionManager`, and the client side reads them over a `Peer` with a `SubscriptionRouter`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::{self, Map, Value};
+
+use crate::{RpcResult, RpcServerResult};
+use crate::client::{connection_closed, to_params};
+use crate::peer::{Peer, SharedWriter};
+use crate::proto::{self, Request, Response};
+use crate::proto::spec::errors;
+use crate::proto::trans::{GetRequest, SendRequest, SendResponse};
+use crate::router::Dispatcher;
+use crate::server::{self, Halves};
+
+struct ManagerShared {
+    method: String,
+    writer: Mutex<Box<dyn SendRequest + Send>>,
+    // Set to `None` once the connection is closed
+    active: Mutex<Option<HashSet<String>>>,
+    next_id: AtomicU64,
+}
+
+/// Server side registry of the subscriptions opened on one connection.
+///
+/// Handlers call `subscribe` and hand the id of the returned `Sink` back to the client, then push
+/// notifications through the sink from anywhere. Once the connection closes, every sink stops.
+#[derive(Clone)]
+pub struct SubscriptionManager {
+    shared: Arc<ManagerShared>,
+}
+
+impl SubscriptionManager {
+    /// Creates a registry sending notifications named `method` through `writer`.
+    pub fn new<W: SendRequest + Send + 'static>(method: &str, writer: W) -> SubscriptionManager {
+        SubscriptionManager {
+            shared: Arc::new(ManagerShared {
+                method: method.to_owned(),
+                writer: Mutex::new(Box::new(writer)),
+                active: Mutex::new(Some(HashSet::new())),
+                next_id: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    /// Opens a subscription, failing if the connection is already closed.
+    pub fn subscribe(&self) -> RpcServerResult<Sink> {
+        let id = format!("0x{:x}", self.shared.next_id.fetch_add(1, Ordering::SeqCst));
+
+        match *self.shared.active.lock().unwrap() {
+            Some(ref mut active) => active.insert(id.clone()),
+            None => return Err(errors::InternalError::with_detail("Connection closed")),
+        };
+
+        Ok(Sink {
+            id,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Closes subscription `id`, returning whether it was open.
+    pub fn unsubscribe(&self, id: &str) -> bool {
+        match *self.shared.active.lock().unwrap() {
+            Some(ref mut active) => active.remove(id),
+            None => false,
+        }
+    }
+
+    /// Closes every subscription, and refuses any new one.
+    pub fn close(&self) {
+        self.shared.active.lock().unwrap().take();
+    }
+
+    /// Number of open subscriptions.
+    pub fn active(&self) -> usize {
+        self.shared.active.lock().unwrap().as_ref().map_or(0, HashSet::len)
+    }
+}
+
+/// Sending end of one subscription.
+pub struct Sink {
+    id: String,
+    shared: Arc<ManagerShared>,
+}
+
+impl Sink {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Whether the subscription is still open, and its connection too.
+    pub fn is_active(&self) -> bool {
+        self.shared.active.lock().unwrap().as_ref().is_some_and(|active| active.contains(&self.id))
+    }
+
+    /// Pushes `result` to the client, failing once the subscription is closed.
+    pub fn notify<T: Serialize>(&self, result: T) -> RpcResult<()> {
+        if !self.is_active() {
+            return Err(connection_closed(&format!("subscription {} is closed", self.id)).into());
+        }
+
+        let mut params = Map::new();
+        params.insert("subscription".to_owned(), Value::String(self.id.clone()));
+        params.insert("result".to_owned(), serde_json::to_value(result).map_err(proto::Error::EncoderError)?);
+
+        let notification = Request::new_notify(self.shared.method.clone(), Some(Value::Object(params)));
+        self.shared.writer.lock().unwrap().request(notification).map_err(From::from)
+    }
+}
+
+/// Answers the requests read from `reader` like `server::serve_connection`, sharing `writer` with
+/// the subscriptions of the dispatcher that `build` creates for this connection.
+///
+/// Every subscription is closed when the connection is.
+pub fn serve_connection<R, W, B, D>(reader: &mut R, writer: W, method: &str, build: B) -> proto::Result<()>
+    where R: GetRequest,
+          W: SendRequest + SendResponse + Send + 'static,
+          B: FnOnce(&SubscriptionManager) -> D,
+          D: Dispatcher
+{
+    let writer = Arc::new(Mutex::new(writer));
+    let manager = SubscriptionManager::new(method, SharedWriter(writer.clone()));
+    let dispatcher = build(&manager);
+
+    let mut halves = Halves {
+        reader,
+        writer: SharedWriter(writer),
+    };
+    let result = server::serve_connection(&mut halves, &dispatcher);
+
+    manager.close();
+    result
+}
+
+struct Streams {
+    senders: HashMap<String, Sender<Value>>,
+    // Notifications that may belong to a subscription whose id is not known yet
+    early: HashMap<String, Vec<Value>>,
+    subscribing: usize,
+}
+
+/// Client side dispatcher, routing the notifications named `method` to their `Subscription`.
+///
+/// Every other request goes to the inner dispatcher. The streams end once the dispatcher is
+/// dropped, which `Peer` does when the connection closes.
+pub struct SubscriptionRouter<D> {
+    method: String,
+    streams: Arc<Mutex<Streams>>,
+    inner: D,
+}
+
+impl<D: Dispatcher> SubscriptionRouter<D> {
+    pub fn new(method: &str, inner: D) -> SubscriptionRouter<D> {
+        SubscriptionRouter {
+            method: method.to_owned(),
+            streams: Arc::new(Mutex::new(Streams {
+                senders: HashMap::new(),
+                early: HashMap::new(),
+                subscribing: 0,
+            })),
+            inner,
+        }
+    }
+
+    /// Handle opening subscriptions routed by this dispatcher.
+    pub fn subscriptions(&self) -> Subscriptions {
+        Subscriptions {
+            streams: Arc::downgrade(&self.streams),
+        }
+    }
+
+    fn route(&self, params: Option<Value>) {
+        let (id, result) = match params {
+            Some(Value::Object(mut params)) => {
+                match (params.remove("subscription"), params.remove("result")) {
+                    (Some(Value::String(id)), Some(result)) => (id, result),
+                    (id, _) => {
+                        warn!("Dropping malformed notification of subscription {:?}", id);
+                        return;
+                    }
+                }
+            },
+            params => {
+                warn!("Dropping malformed subscription notification {:?}", params);
+                return;
+            }
+        };
+
+        let mut streams = self.streams.lock().unwrap();
+        match streams.senders.get(&id) {
+            Some(sender) => {
+                // The subscription may have been dropped already
+                let _ = sender.send(result);
+            },
+            None if streams.subscribing > 0 => streams.early.entry(id).or_default().push(result),
+            None => warn!("Dropping notification of unknown subscription {}", id),
+        }
+    }
+}
+
+impl<D: Dispatcher> Dispatcher for SubscriptionRouter<D> {
+    fn dispatch(&self, req: Request) -> Option<Response> {
+        if req.method == self.method && req.id.is_none() {
+            self.route(req.params);
+            None
+        } else {
+            self.inner.dispatch(req)
+        }
+    }
+}
+
+/// Opens subscriptions whose notifications are read by a `SubscriptionRouter`.
+#[derive(Clone)]
+pub struct Subscriptions {
+    streams: Weak<Mutex<Streams>>,
+}
+
+impl Subscriptions {
+    /// Calls `method`, which returns the id of a new subscription, and starts collecting its
+    /// notifications.
+    pub fn subscribe<P: Serialize>(&self, peer: &Peer, method: &str, params: P) -> RpcResult<Subscription> {
+        let params = to_params(params)?;
+
+        // Notifications may come before the response, so they are kept until it arrives
+        self.with_streams(|streams| streams.subscribing += 1)?;
+        let result = peer.call::<_, String>(method, params);
+
+        self.with_streams(|streams| {
+            streams.subscribing -= 1;
+            let early = match result {
+                Ok(ref id) => streams.early.remove(id).unwrap_or_default(),
+                Err(..) => Vec::new(),
+            };
+            if streams.subscribing == 0 {
+                streams.early.clear();
+            }
+
+            let id = result?;
+            let (sender, receiver) = mpsc::channel();
+            for value in early {
+                let _ = sender.send(value);
+            }
+            streams.senders.insert(id.clone(), sender);
+
+            Ok(Subscription {
+                id,
+                receiver,
+                streams: self.streams.clone(),
+            })
+        })?
+    }
+
+    fn with_streams<T, F: FnOnce(&mut Streams) -> T>(&self, f: F) -> RpcResult<T> {
+        match self.streams.upgrade() {
+            Some(streams) => Ok(f(&mut streams.lock().unwrap())),
+            None => Err(connection_closed("subscription router dropped").into()),
+        }
+    }
+}
+
+/// Notifications received for one subscription, in the order they were sent.
+///
+/// Iterating ends when the connection closes.
+pub struct Subscription {
+    id: String,
+    receiver: Receiver<Value>,
+    streams: Weak<Mutex<Streams>>,
+}
+
+impl Subscription {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Waits for the next notification, `None` once the connection is closed.
+    pub fn recv(&self) -> Option<Value> {
+        self.receiver.recv().ok()
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Value, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// Calls `method` to close the subscription on the server, returning its result.
+    pub fn unsubscribe(self, peer: &Peer, method: &str) -> RpcResult<bool> {
+        peer.call(method, (&self.id,))
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        self.recv()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(streams) = self.streams.upgrade() {
+            streams.lock().unwrap().senders.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{BufReader, BufWriter};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use crate::peer::Peer;
+    use crate::proto::spec::{ServerReader, ServerWriter};
+    use crate::router::Router;
+
+    use super::{serve_connection, SubscriptionRouter};
+
+    #[test]
+    fn test_pubsub() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stopped_tx, stopped_rx) = mpsc::channel();
+
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = ServerReader::new(BufReader::new(stream.try_clone().unwrap()));
+            let writer = ServerWriter::new(BufWriter::new(stream));
+
+            serve_connection(&mut reader, writer, "subscription", |manager| {
+                let mut router = Router::new();
+
+                // Pushes `count` numbers right away, possibly before the subscription id is returned
+                let subscriptions = manager.clone();
+                router.add_method("count", move |(count,): (u64,)| {
+                    let sink = subscriptions.subscribe()?;
+                    for i in 0..count {
+                        sink.notify(i).unwrap();
+                    }
+                    Ok(sink.id().to_owned())
+                });
+
+                // Ticks until the subscription or the connection is closed
+                let subscriptions = manager.clone();
+                let stopped_tx = stopped_tx.clone();
+                router.add_method("tick", move |()| {
+                    let sink = subscriptions.subscribe()?;
+                    let id = sink.id().to_owned();
+                    let stopped_tx = stopped_tx.clone();
+                    thread::spawn(move|| {
+                        while sink.notify("tick").is_ok() {
+                            thread::sleep(Duration::from_millis(5));
+                        }
+                        stopped_tx.send(sink.id().to_owned()).unwrap();
+                    });
+                    Ok(id)
+                });
+
+                let subscriptions = manager.clone();
+                router.add_method("unsubscribe", move |(id,): (String,)| Ok(subscriptions.unsubscribe(&id)));
+
+                router
+            })
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let conn = stream.try_clone().unwrap();
+        let router = SubscriptionRouter::new("subscription", Router::new());
+        let subscriptions = router.subscriptions();
+        let peer = Peer::from_tcp(stream, router).unwrap();
+
+        let counter = subscriptions.subscribe(&peer, "count", (3,)).unwrap();
+        assert_eq!(vec![Value::from(0), Value::from(1), Value::from(2)], counter.take(3).collect::<Vec<_>>());
+
+        let ticker = subscriptions.subscribe(&peer, "tick", ()).unwrap();
+        let ticker_id = ticker.id().to_owned();
+        assert_eq!(Value::from("tick"), ticker.recv().unwrap());
+        assert!(ticker.unsubscribe(&peer, "unsubscribe").unwrap());
+        assert_eq!(ticker_id, stopped_rx.recv().unwrap());
+        assert!(!peer.call::<_, bool>("unsubscribe", (ticker_id,)).unwrap());
+
+        // Closing the connection ends the subscriptions on both sides
+        let mut ticker = subscriptions.subscribe(&peer, "tick", ()).unwrap();
+        assert_eq!(Value::from("tick"), ticker.recv().unwrap());
+        conn.shutdown(Shutdown::Both).unwrap();
+        server.join().unwrap().unwrap();
+        assert_eq!(ticker.id(), stopped_rx.recv().unwrap());
+        assert!(ticker.all(|v| v == "tick"));
+        assert!(subscriptions.subscribe(&peer, "tick", ()).is_err());
+    }
+}
@@ -0,0 +1,462 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::thread;
+use std::vec;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Map, Value};
+
+use crate::RpcServerResult;
+use crate::proto::{Id, ProtocolError, Request, Response};
+use crate::proto::spec::errors;
+use crate::proto::trans::{ClientRequest, ServerResponse};
+
+/// Turns requests into responses.
+pub trait Dispatcher {
+    /// Handles a single request, returning `None` if it is a notification.
+    fn dispatch(&self, req: Request) -> Option<Response>;
+
+    /// Handles a single request or a whole batch, returning `None` if there is nothing to reply.
+    fn dispatch_request(&self, req: ClientRequest) -> Option<ServerResponse> {
+        match req {
+            ClientRequest::Single(req) => self.dispatch(req).map(ServerResponse::Single),
+            ClientRequest::Batch(reqs) => {
+                let resps = reqs.into_iter()
+                                .filter_map(|r| dispatch_element(self, r))
+                                .collect::<Vec<Response>>();
+
+                batch_response(resps)
+            }
+        }
+    }
+}
+
+/// Handles one element of a batch, answering an invalid one with its error.
+///
+/// A panic is answered with an `InternalError`, and leaves the rest of the batch alone.
+fn dispatch_element<D: Dispatcher + ?Sized>(dispatcher: &D, req: Result<Request, ProtocolError>) -> Option<Response> {
+    match req {
+        Ok(req) => dispatch_caught(dispatcher, req),
+        Err(err) => Some(Response::error(err, Id::Null)),
+    }
+}
+
+/// Handles `req`, answering it with an `InternalError` if the dispatcher panics.
+pub(crate) fn dispatch_caught<D: Dispatcher + ?Sized>(dispatcher: &D, req: Request) -> Option<Response> {
+    let id = req.id.clone();
+    let method = req.method.clone();
+
+    match panic::catch_unwind(AssertUnwindSafe(|| dispatcher.dispatch(req))) {
+        Ok(resp) => resp,
+        Err(payload) => id.map(|id| Response::error(panicked(&method, &*payload), id)),
+    }
+}
+
+pub(crate) fn panicked(method: &str, payload: &(dyn Any + Send)) -> ProtocolError {
+    let cause = match payload.downcast_ref::<&'static str>() {
+        Some(msg) => msg,
+        None => payload.downcast_ref::<String>().map_or("unknown cause", |msg| &msg[..]),
+    };
+
+    let detail = format!("Method {:?} panicked: {}", method, cause);
+    error!("{}", detail);
+    errors::InternalError::with_detail(detail)
+}
+
+/// Answers a request whose handler panics with an `InternalError`.
+///
+/// A batch is handed to the inner dispatcher whole, so that it may run it its own way. Its
+/// elements are caught one by one, see `Dispatcher::dispatch_request`.
+pub(crate) struct CatchPanic<'a, D: ?Sized>(pub(crate) &'a D);
+
+impl<D: Dispatcher + ?Sized> Dispatcher for CatchPanic<'_, D> {
+    fn dispatch(&self, req: Request) -> Option<Response> {
+        dispatch_caught(self.0, req)
+    }
+
+    fn dispatch_request(&self, req: ClientRequest) -> Option<ServerResponse> {
+        match req {
+            ClientRequest::Single(req) => self.dispatch(req).map(ServerResponse::Single),
+            batch => self.0.dispatch_request(batch),
+        }
+    }
+}
+
+/// Wraps the responses of a batch, which must not be answered at all if it only had notifications.
+pub(crate) fn batch_response(resps: Vec<Response>) -> Option<ServerResponse> {
+    if resps.is_empty() {
+        None
+    } else {
+        Some(ServerResponse::Batch(resps))
+    }
+}
+
+/// Implementation of a method registered in a `Router`.
+pub trait Handler: Send + Sync {
+    fn handle(&self, req: Request) -> RpcServerResult<Value>;
+}
+
+struct MethodHandler<F, P, R> {
+    f: F,
+    _marker: PhantomData<fn(P) -> R>,
+}
+
+impl<P, R, F> Handler for MethodHandler<F, P, R>
+    where P: DeserializeOwned,
+          R: Serialize,
+          F: Fn(P) -> RpcServerResult<R> + Send + Sync
+{
+    fn handle(&self, req: Request) -> RpcServerResult<Value> {
+        let params = req.parse_params()?;
+        let result = (self.f)(params)?;
+        serde_json::to_value(result)
+            .map_err(|err| errors::InternalError::with_detail(err.to_string()))
+    }
+}
+
+/// Arguments of a request, taken one at a time either by position or by name.
+///
+/// Arguments omitted by the caller are taken as `null`, so that they can be received as `Option`.
+pub struct Params {
+    names: &'static [&'static str],
+    index: usize,
+    values: ParamValues,
+}
+
+enum ParamValues {
+    Positional(vec::IntoIter<Value>),
+    Named(Map<String, Value>),
+}
+
+impl Params {
+    pub fn new(params: Option<Value>, names: &'static [&'static str]) -> RpcServerResult<Params> {
+        let values = match params {
+            None | Some(Value::Null) => ParamValues::Positional(Vec::new().into_iter()),
+            Some(Value::Array(arr)) => {
+                if arr.len() > names.len() {
+                    return Err(errors::InvalidParams::with_detail(
+                            format!("Expecting at most {} params, but found {}", names.len(), arr.len())));
+                }
+                ParamValues::Positional(arr.into_iter())
+            },
+            Some(Value::Object(obj)) => ParamValues::Named(obj),
+            Some(params) => {
+                return Err(errors::InvalidParams::with_detail(
+                        format!("Expecting params to be an Array or an Object, but found {}", params)));
+            }
+        };
+
+        Ok(Params {
+            names,
+            index: 0,
+            values,
+        })
+    }
+
+    pub fn take<T: DeserializeOwned>(&mut self) -> RpcServerResult<T> {
+        let name = self.names.get(self.index).cloned().unwrap_or_default();
+        self.index += 1;
+
+        let value = match self.values {
+            ParamValues::Positional(ref mut iter) => iter.next(),
+            ParamValues::Named(ref mut obj) => obj.remove(name),
+        };
+
+        serde_json::from_value(value.unwrap_or(Value::Null)).map_err(|err| {
+            errors::InvalidParams::with_detail(format!("Invalid param `{}`: {}", name, err))
+        })
+    }
+}
+
+/// Dispatches requests to the handlers registered by method name.
+///
+/// Requests for unknown methods are answered with `MethodNotFound`, those whose handler panics
+/// with `InternalError`, and the responses of notifications are dropped. The elements of a batch
+/// are handled in turn, unless `set_batch_concurrency` allows more.
+pub struct Router {
+    methods: HashMap<String, Box<dyn Handler>>,
+    batch_concurrency: usize,
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            methods: HashMap::new(),
+            batch_concurrency: 1,
+        }
+    }
+
+    /// Sets how many elements of a batch may be handled at once, each on its own thread.
+    ///
+    /// Responses keep the order of their requests either way.
+    pub fn set_batch_concurrency(&mut self, concurrency: usize) -> &mut Router {
+        assert!(concurrency > 0, "batch concurrency must be at least 1");
+        self.batch_concurrency = concurrency;
+        self
+    }
+
+    pub fn batch_concurrency(&self) -> usize {
+        self.batch_concurrency
+    }
+
+    /// Registers a closure taking the deserialized params of the request.
+    ///
+    /// Params that do not match `P` are answered with `InvalidParams`.
+    pub fn add_method<P, R, F>(&mut self, method: &str, f: F) -> &mut Router
+        where P: DeserializeOwned + 'static,
+              R: Serialize + 'static,
+              F: Fn(P) -> RpcServerResult<R> + Send + Sync + 'static
+    {
+        self.add_handler(method, Box::new(MethodHandler { f, _marker: PhantomData }))
+    }
+
+    pub fn add_handler(&mut self, method: &str, handler: Box<dyn Handler>) -> &mut Router {
+        self.methods.insert(method.to_owned(), handler);
+        self
+    }
+
+    pub fn has_method(&self, method: &str) -> bool {
+        self.methods.contains_key(method)
+    }
+}
+
+impl Dispatcher for Router {
+    fn dispatch(&self, req: Request) -> Option<Response> {
+        trace!("Dispatching request {:?}", req);
+
+        let id = req.id.clone();
+        let result = match self.methods.get(&req.method) {
+            Some(handler) => {
+                let method = req.method.clone();
+                panic::catch_unwind(AssertUnwindSafe(|| handler.handle(req)))
+                    .unwrap_or_else(|payload| Err(panicked(&method, &*payload)))
+            },
+            None => {
+                Err(errors::MethodNotFound::with_detail(
+                        Value::String(format!("Unknown method {:?}", req.method))))
+            }
+        };
+
+        id.map(|id| Response::new(result, id))
+    }
+
+    fn dispatch_request(&self, req: ClientRequest) -> Option<ServerResponse> {
+        match req {
+            ClientRequest::Batch(reqs) if self.batch_concurrency > 1 && reqs.len() > 1 => {
+                batch_response(self.dispatch_parallel(reqs))
+            },
+            ClientRequest::Batch(reqs) => {
+                batch_response(reqs.into_iter().filter_map(|r| dispatch_element(self, r)).collect())
+            },
+            ClientRequest::Single(req) => self.dispatch(req).map(ServerResponse::Single),
+        }
+    }
+}
+
+impl Router {
+    fn dispatch_parallel(&self, reqs: Vec<Result<Request, ProtocolError>>) -> Vec<Response> {
+        let workers = self.batch_concurrency.min(reqs.len());
+        let jobs = Mutex::new(reqs.into_iter().enumerate());
+
+        let mut resps = thread::scope(|scope| {
+            let handles = (0..workers).map(|_| {
+                scope.spawn(|| {
+                    let mut resps = Vec::new();
+                    loop {
+                        let job = jobs.lock().unwrap().next();
+                        match job {
+                            Some((idx, req)) => resps.extend(dispatch_element(self, req).map(|resp| (idx, resp))),
+                            None => break resps,
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+
+            handles.into_iter()
+                   .flat_map(|handle| handle.join().unwrap_or_else(|payload| panic::resume_unwind(payload)))
+                   .collect::<Vec<_>>()
+        });
+
+        resps.sort_by_key(|&(idx, _)| idx);
+        resps.into_iter().map(|(_, resp)| resp).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::{self, Value};
+
+    use crate::proto::{Id, Response};
+    use crate::proto::spec::errors;
+    use crate::proto::trans::{ClientRequest, ServerResponse};
+
+    use super::{Dispatcher, Router};
+
+    fn calculator() -> Router {
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b))
+              .add_method("touch", |_: Value| Ok(()))
+              .add_method("zero", |()| Ok(0));
+        router
+    }
+
+    fn request(s: &str) -> ClientRequest {
+        serde_json::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_router_single() {
+        let router = calculator();
+
+        let resp = router.dispatch_request(request("{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}"));
+        assert_eq!(Some(ServerResponse::Single(Response::result(Value::from(3), 1))), resp);
+
+        let resp = router.dispatch_request(request("{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[\"a\"],\"id\":2}"));
+        match resp {
+            Some(ServerResponse::Single(resp)) => {
+                assert_eq!(errors::ERRCODE_INVALID_PARAMS, resp.into_result().unwrap_err().code);
+            },
+            other => panic!("Expecting InvalidParams, but found {:?}", other),
+        }
+
+        let resp = router.dispatch_request(request("{\"jsonrpc\":\"2.0\",\"method\":\"sub\",\"id\":3}"));
+        match resp {
+            Some(ServerResponse::Single(resp)) => {
+                assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, resp.into_result().unwrap_err().code);
+            },
+            other => panic!("Expecting MethodNotFound, but found {:?}", other),
+        }
+
+        let resp = router.dispatch_request(request("{\"jsonrpc\":\"2.0\",\"method\":\"touch\"}"));
+        assert_eq!(None, resp);
+
+        // No params at all, however they are sent
+        for params in ["", ",\"params\":null", ",\"params\":[]"] {
+            let resp = router.dispatch_request(request(&format!("{{\"jsonrpc\":\"2.0\",\"method\":\"zero\"{},\"id\":4}}", params)));
+            assert_eq!(Some(ServerResponse::Single(Response::result(Value::from(0), 4))), resp);
+        }
+    }
+
+    #[test]
+    fn test_router_batch() {
+        let router = calculator();
+
+        let resp = router.dispatch_request(request("[{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1},\
+                                                     {\"jsonrpc\":\"2.0\",\"method\":\"touch\"},1]"));
+        match resp {
+            Some(ServerResponse::Batch(resps)) => {
+                assert_eq!(2, resps.len());
+                assert_eq!(Response::result(Value::from(3), 1), resps[0]);
+                assert_eq!(&Id::Null, resps[1].id());
+            },
+            other => panic!("Expecting a batch, but found {:?}", other),
+        }
+
+        let resp = router.dispatch_request(request("[{\"jsonrpc\":\"2.0\",\"method\":\"touch\"},\
+                                                     {\"jsonrpc\":\"2.0\",\"method\":\"touch\"}]"));
+        assert_eq!(None, resp);
+    }
+
+    #[test]
+    fn test_router_parallel_batch() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+
+        let mut router = calculator();
+        {
+            let (running, most) = (running.clone(), most.clone());
+            router.add_method("sleep", move |(ms,): (u64,)| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(ms));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(ms)
+            });
+        }
+        router.set_batch_concurrency(2);
+
+        // Later elements finish first, but responses keep the order of the batch
+        let resp = router.dispatch_request(request("[{\"jsonrpc\":\"2.0\",\"method\":\"sleep\",\"params\":[60],\"id\":1},\
+                                                     {\"jsonrpc\":\"2.0\",\"method\":\"sleep\",\"params\":[40]},\
+                                                     {\"jsonrpc\":\"2.0\",\"method\":\"sleep\",\"params\":[20],\"id\":3},\
+                                                     1,\
+                                                     {\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":5}]"));
+        match resp {
+            Some(ServerResponse::Batch(resps)) => {
+                assert_eq!(4, resps.len());
+                assert_eq!(Response::result(Value::from(60), 1), resps[0]);
+                assert_eq!(Response::result(Value::from(20), 3), resps[1]);
+                assert_eq!(&Id::Null, resps[2].id());
+                assert_eq!(Response::result(Value::from(3), 5), resps[3]);
+            },
+            other => panic!("Expecting a batch, but found {:?}", other),
+        }
+        assert_eq!(2, most.load(Ordering::SeqCst));
+
+        let resp = router.dispatch_request(request("[{\"jsonrpc\":\"2.0\",\"method\":\"sleep\",\"params\":[1]},\
+                                                     {\"jsonrpc\":\"2.0\",\"method\":\"touch\"}]"));
+        assert_eq!(None, resp);
+    }
+
+    #[test]
+    fn test_router_panic() {
+        let mut router = calculator();
+        router.add_method("panic", |()| -> crate::RpcServerResult<()> { panic!("on purpose") });
+
+        // Only the panicking element is answered with an error, whether the batch runs in parallel or not
+        for concurrency in [1, 2] {
+            router.set_batch_concurrency(concurrency);
+            let resp = router.dispatch_request(request("[{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1},\
+                                                         {\"jsonrpc\":\"2.0\",\"method\":\"panic\",\"id\":2},\
+                                                         1]"));
+            match resp {
+                Some(ServerResponse::Batch(resps)) => {
+                    assert_eq!(3, resps.len());
+                    assert_eq!(Response::result(Value::from(3), 1), resps[0]);
+                    assert_eq!(&Id::Number(2), resps[1].id());
+                    assert_eq!(errors::ERRCODE_INTERNAL_ERROR, resps[1].clone().into_result().unwrap_err().code);
+                    assert_eq!(&Id::Null, resps[2].id());
+                    assert_eq!(errors::ERRCODE_INVALID_REQUEST, resps[2].clone().into_result().unwrap_err().code);
+                },
+                other => panic!("Expecting a batch, but found {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,399 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Server runtime serving connections on a pool of worker threads, with graceful shutdown.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::Shutdown;
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::proto;
+use crate::proto::spec::ServerStream;
+use crate::proto::trans::SendResponse;
+use crate::router::{CatchPanic, Dispatcher};
+use crate::server::{hung_up, next_request, Buffered, Connection, Server};
+
+/// How long the accept loop sleeps when no connection is waiting.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a connection may stay silent before it is closed, by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ConnState {
+    busy: bool,
+    // Shuts the connection down, waking up its worker if it is blocked reading
+    closer: Box<dyn Fn(Shutdown) + Send>,
+}
+
+struct State {
+    // Set by `shutdown`, the deadline of the requests still in flight
+    deadline: Option<Instant>,
+    connections: HashMap<u64, ConnState>,
+    next_key: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Serves the connections of a `Server` on a pool of worker threads.
+///
+/// Each worker serves one connection at a time, so connections beyond the pool size wait for a
+/// worker to be free. A connection silent for longer than the idle timeout is closed, so that idle
+/// clients cannot hold every worker. A handler that panics is answered with an `InternalError`,
+/// and neither the connection nor the worker is lost.
+pub struct Runtime<D> {
+    dispatcher: Arc<D>,
+    workers: usize,
+    idle_timeout: Option<Duration>,
+    shared: Arc<Shared>,
+}
+
+impl<D: Dispatcher + Send + Sync + 'static> Runtime<D> {
+    /// Creates a runtime with one worker per CPU.
+    pub fn new(dispatcher: D) -> Runtime<D> {
+        Runtime {
+            dispatcher: Arc::new(dispatcher),
+            workers: thread::available_parallelism().map_or(4, NonZeroUsize::get),
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    deadline: None,
+                    connections: HashMap::new(),
+                    next_key: 0,
+                }),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    pub fn set_workers(&mut self, workers: usize) {
+        assert!(workers > 0, "a runtime needs at least one worker");
+        self.workers = workers;
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// How long a worker waits for the next request on a connection before closing it, `None`
+    /// waiting forever.
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Handle stopping `run` from another thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Serves the connections accepted by `server` until `ShutdownHandle::shutdown` is called.
+    ///
+    /// It then stops accepting, and returns once every request in flight is answered, or once the
+    /// shutdown deadline has passed, dropping whatever is left.
+    pub fn run<S>(&self, mut server: S) -> io::Result<()>
+        where S: Server,
+              S::Stream: Connection + Send + 'static,
+              S::Addr: Send + 'static
+    {
+        server.set_nonblocking(true)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..self.workers {
+            let receiver = receiver.clone();
+            let dispatcher = self.dispatcher.clone();
+            let shared = self.shared.clone();
+            let idle_timeout = self.idle_timeout;
+            thread::spawn(move|| work(receiver, dispatcher, shared, idle_timeout));
+        }
+
+        while self.shared.state.lock().unwrap().deadline.is_none() {
+            let (stream, peer_addr) = match server.accept() {
+                Ok(conn) => conn,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                    continue;
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            debug!("Got connection from {:?}", peer_addr);
+
+            let key = match self.shared.register(&stream) {
+                Ok(key) => key,
+                Err(err) => {
+                    error!("Dropping connection from {:?}: {}", peer_addr, err);
+                    continue;
+                }
+            };
+            // Workers only stop once the sender is dropped
+            let _ = sender.send((key, stream, peer_addr));
+        }
+
+        drop(server);
+        drop(sender);
+        self.shared.drain();
+        Ok(())
+    }
+}
+
+/// Stops a `Runtime` from another thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections, and gives the requests in flight `timeout` to complete.
+    ///
+    /// Idle connections stop reading right away, and busy ones once their current request is
+    /// answered. A request already read when the shutdown starts is still answered.
+    ///
+    /// This does not wait: `Runtime::run` returns once the runtime is drained.
+    pub fn shutdown(&self, timeout: Duration) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.deadline.is_some() {
+            return;
+        }
+        state.deadline = Some(Instant::now() + timeout);
+
+        // Only the reading side is closed, so that a request read in the meantime is still answered
+        for conn in state.connections.values().filter(|conn| !conn.busy) {
+            (conn.closer)(Shutdown::Read);
+        }
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Shared {
+    fn register<C: Connection + Send + 'static>(&self, stream: &C) -> io::Result<u64> {
+        let handle = stream.try_clone()?;
+        let closer = Box::new(move|how| {
+            if let Err(err) = handle.shutdown(how) {
+                debug!("Failed to shut down connection: {}", err);
+            }
+        });
+
+        let mut state = self.state.lock().unwrap();
+        let key = state.next_key;
+        state.next_key += 1;
+        state.connections.insert(key, ConnState { busy: false, closer });
+        Ok(key)
+    }
+
+    /// Marks connection `key` as busy.
+    ///
+    /// The request is answered even if a shutdown has started, as it was read already.
+    fn begin(&self, key: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(conn) = state.connections.get_mut(&key) {
+            conn.busy = true;
+        }
+    }
+
+    /// Marks connection `key` as idle, returning whether it may serve another request.
+    fn finish(&self, key: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if let Some(conn) = state.connections.get_mut(&key) {
+            conn.busy = false;
+        }
+        state.deadline.is_none()
+    }
+
+    fn remove(&self, key: u64) {
+        self.state.lock().unwrap().connections.remove(&key);
+        self.changed.notify_all();
+    }
+
+    /// Waits for every connection to close, closing them all at the shutdown deadline.
+    fn drain(&self) {
+        let mut state = self.state.lock().unwrap();
+        let deadline = state.deadline.unwrap_or_else(Instant::now);
+
+        while !state.connections.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Dropping {} connections still busy at the shutdown deadline", state.connections.len());
+                for (_, conn) in state.connections.drain() {
+                    (conn.closer)(Shutdown::Both);
+                }
+                break;
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+}
+
+type Job<C, A> = (u64, C, A);
+
+fn work<C, A, D>(receiver: Arc<Mutex<Receiver<Job<C, A>>>>, dispatcher: Arc<D>, shared: Arc<Shared>,
+                 idle_timeout: Option<Duration>)
+    where C: Connection,
+          A: fmt::Debug,
+          D: Dispatcher
+{
+    loop {
+        // The lock is released as soon as a job is taken
+        let job = receiver.lock().unwrap().recv();
+        let (key, stream, peer_addr) = match job {
+            Ok(job) => job,
+            Err(..) => break,
+        };
+
+        // A dispatcher of its own batches may still panic, which only costs the connection
+        match panic::catch_unwind(AssertUnwindSafe(|| serve_connection(key, stream, &*dispatcher, &shared, idle_timeout))) {
+            Ok(Ok(())) => debug!("Connection from {:?} closed", peer_addr),
+            Ok(Err(err)) => error!("Connection from {:?} failed: {}", peer_addr, err),
+            Err(..) => error!("Connection from {:?} dropped after a panic", peer_addr),
+        }
+        shared.remove(key);
+    }
+}
+
+fn serve_connection<C, D>(key: u64, stream: C, dispatcher: &D, shared: &Shared, idle_timeout: Option<Duration>)
+                          -> proto::Result<()>
+    where C: Connection,
+          D: Dispatcher
+{
+    hung_up(serve_requests(key, stream, dispatcher, shared, idle_timeout))
+}
+
+fn serve_requests<C, D>(key: u64, stream: C, dispatcher: &D, shared: &Shared, idle_timeout: Option<Duration>)
+                        -> proto::Result<()>
+    where C: Connection,
+          D: Dispatcher
+{
+    stream.set_read_timeout(idle_timeout)?;
+    let mut transport = ServerStream::new(Buffered::new(stream));
+    let dispatcher = CatchPanic(dispatcher);
+
+    loop {
+        let req = match next_request(&mut transport) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            // The client stayed silent for too long, between two requests or in the middle of one
+            Err(proto::Error::IoError(ref err))
+                if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                debug!("Closing a connection idle for {:?}", idle_timeout.unwrap_or_default());
+                return Ok(());
+            },
+            Err(err) => return Err(err),
+        };
+        trace!("Request {:?}", req);
+
+        shared.begin(key);
+        let result = match dispatcher.dispatch_request(req) {
+            Some(resp) => {
+                trace!("Response {:?}", resp);
+                transport.server_response(resp)
+            },
+            None => Ok(()),
+        };
+        let more = shared.finish(key);
+
+        result?;
+        if !more {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::{BufReader, Write};
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use crate::proto::Response;
+    use crate::proto::spec::ClientReader;
+    use crate::proto::trans::{GetResponse, ServerResponse};
+    use crate::router::Router;
+    use crate::server::TcpServer;
+
+    use super::{serve_connection, Runtime};
+
+    #[test]
+    fn test_runtime_shutdown_pending() {
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+        let runtime = Runtime::new(router);
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let key = runtime.shared.register(&server).unwrap();
+
+        // The request is sent while the connection is still idle, but only read after the shutdown
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}").unwrap();
+        runtime.shutdown_handle().shutdown(Duration::from_secs(5));
+        serve_connection(key, server, &*runtime.dispatcher, &runtime.shared, None).unwrap();
+        runtime.shared.remove(key);
+
+        let mut reader = ClientReader::new(BufReader::new(client));
+        assert_eq!(Some(ServerResponse::Single(Response::result(Value::from(3), 1))), reader.get_response().unwrap());
+        assert_eq!(None, reader.get_response().unwrap());
+    }
+
+    #[test]
+    fn test_runtime_idle_connections() {
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+        let mut runtime = Runtime::new(router);
+        runtime.set_workers(2);
+        runtime.set_idle_timeout(Some(Duration::from_millis(100)));
+        let handle = runtime.shutdown_handle();
+
+        let server = TcpServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let running = thread::spawn(move|| runtime.run(server));
+
+        // More idle clients than workers, which are closed in time for the next one to be served
+        let idle = (0..4).map(|_| TcpStream::connect(addr).unwrap()).collect::<Vec<_>>();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}").unwrap();
+
+        let mut reader = ClientReader::new(BufReader::new(client));
+        assert_eq!(Some(ServerResponse::Single(Response::result(Value::from(3), 1))), reader.get_response().unwrap());
+
+        handle.shutdown(Duration::from_secs(5));
+        running.join().unwrap().unwrap();
+        drop(idle);
+    }
+}
@@ -0,0 +1,496 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! Listeners accepting connections, and the loop serving them with a `Dispatcher`.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{self, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{self as unix_net, UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::proto::{self, Id, Response};
+use crate::proto::spec::ServerStream;
+use crate::proto::trans::{ClientRequest, GetRequest, SendResponse};
+use crate::router::Dispatcher;
+
+/// Source of connections, such as a listening socket.
+pub trait Server {
+    type Stream: Read + Write;
+    type Addr: fmt::Debug;
+
+    fn accept(&mut self) -> io::Result<(Self::Stream, Self::Addr)>;
+
+    /// Makes `accept` fail with `WouldBlock` instead of waiting, so that a runtime can stop
+    /// listening. Accepted streams are blocking either way.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+/// Stream that can be shut down from another thread, to stop a server blocked reading it, and
+/// whose reads can time out.
+pub trait Connection: Read + Write {
+    fn try_clone(&self) -> io::Result<Self> where Self: Sized;
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: net::Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+pub struct TcpServer {
+    listener: TcpListener,
+}
+
+impl TcpServer {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpServer> {
+        TcpListener::bind(addr).map(TcpServer::from_listener)
+    }
+
+    pub fn from_listener(listener: TcpListener) -> TcpServer {
+        TcpServer {
+            listener,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn get_ref(&self) -> &TcpListener {
+        &self.listener
+    }
+}
+
+impl Server for TcpServer {
+    type Stream = TcpStream;
+    type Addr = net::SocketAddr;
+
+    fn accept(&mut self) -> io::Result<(TcpStream, net::SocketAddr)> {
+        let (stream, addr) = self.listener.accept()?;
+        // Some platforms let the stream inherit the mode of the listener
+        stream.set_nonblocking(false)?;
+        Ok((stream, addr))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.listener.set_nonblocking(nonblocking)
+    }
+}
+
+/// Server on a Unix domain socket, whose file is removed when the server is dropped.
+#[cfg(unix)]
+pub struct UnixServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixServer {
+    /// Binds the socket at `path`, replacing a stale socket left behind by a dead server.
+    ///
+    /// Fails with `AddrInUse` if a server still accepts connections there, or if `path` is not a
+    /// socket at all.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<UnixServer> {
+        let path = path.as_ref();
+
+        match fs::symlink_metadata(path) {
+            Ok(meta) => {
+                if !meta.file_type().is_socket() {
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                              format!("{} exists and is not a socket", path.display())));
+                }
+
+                match UnixStream::connect(path) {
+                    Ok(..) => {
+                        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                                  format!("{} is used by a running server", path.display())));
+                    },
+                    Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                        debug!("Removing stale socket {}", path.display());
+                        fs::remove_file(path)?;
+                    },
+                    Err(err) => return Err(err),
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+
+        Ok(UnixServer {
+            listener: UnixListener::bind(path)?,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get_ref(&self) -> &UnixListener {
+        &self.listener
+    }
+}
+
+#[cfg(unix)]
+impl Server for UnixServer {
+    type Stream = UnixStream;
+    type Addr = unix_net::SocketAddr;
+
+    fn accept(&mut self) -> io::Result<(UnixStream, unix_net::SocketAddr)> {
+        let (stream, addr) = self.listener.accept()?;
+        stream.set_nonblocking(false)?;
+        Ok((stream, addr))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.listener.set_nonblocking(nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixServer {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!("Failed to remove socket {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// Serves every connection accepted by `server`, one after the other.
+///
+/// A connection failing is logged, and does not stop the server.
+pub fn serve<S, D>(server: &mut S, dispatcher: &D) -> io::Result<()>
+    where S: Server,
+          D: Dispatcher
+{
+    loop {
+        let (stream, peer_addr) = server.accept()?;
+        debug!("Got connection from {:?}", peer_addr);
+
+        match serve_connection(&mut ServerStream::new(Buffered::new(stream)), dispatcher) {
+            Ok(()) => debug!("Connection from {:?} closed", peer_addr),
+            Err(err) => error!("Connection from {:?} failed: {}", peer_addr, err),
+        }
+    }
+}
+
+/// Answers the requests read from `transport` until EOF.
+///
+/// A request that cannot be decoded is answered with its error and a null id, and serving goes
+/// on with the next one. I/O errors end the connection early, and so do framing errors after
+/// which the next message cannot be found. A client hanging up while it is answered is not an
+/// error.
+pub fn serve_connection<T, D>(transport: &mut T, dispatcher: &D) -> proto::Result<()>
+    where T: GetRequest + SendResponse,
+          D: Dispatcher
+{
+    let result = (|| {
+        while let Some(req) = next_request(transport)? {
+            trace!("Request {:?}", req);
+
+            if let Some(resp) = dispatcher.dispatch_request(req) {
+                trace!("Response {:?}", resp);
+                transport.server_response(resp)?;
+            }
+        }
+        Ok(())
+    })();
+    hung_up(result)
+}
+
+/// Turns the error of a client that closed its end of the connection into a normal EOF.
+///
+/// A client may close as soon as it has read the response it waited for.
+pub(crate) fn hung_up(result: proto::Result<()>) -> proto::Result<()> {
+    match result {
+        Err(proto::Error::IoError(ref err))
+            if err.kind() == io::ErrorKind::BrokenPipe || err.kind() == io::ErrorKind::ConnectionReset => {
+            debug!("Client hung up: {}", err);
+            Ok(())
+        },
+        result => result,
+    }
+}
+
+/// Reads the next request, answering those that cannot be decoded, until one can be or EOF.
+///
+/// Only errors that are not recoverable are returned, see `proto::Error::is_recoverable`.
+pub(crate) fn next_request<T: GetRequest + SendResponse>(transport: &mut T) -> proto::Result<Option<ClientRequest>> {
+    loop {
+        match transport.get_request() {
+            Ok(req) => return Ok(req),
+            Err(err @ proto::Error::IoError(..)) => return Err(err),
+            Err(err) => {
+                warn!("Invalid request: {}", err);
+                transport.response(Response::error(err.to_protocol_error(), Id::Null))?;
+                if !err.is_recoverable() {
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+/// Reading and writing halves of a connection, to serve them with `next_request`.
+pub(crate) struct Halves<'a, R, W> {
+    pub(crate) reader: &'a mut R,
+    pub(crate) writer: W,
+}
+
+impl<R: GetRequest, W> GetRequest for Halves<'_, R, W> {
+    fn get_request(&mut self) -> proto::Result<Option<ClientRequest>> {
+        self.reader.get_request()
+    }
+}
+
+impl<R, W: SendResponse> SendResponse for Halves<'_, R, W> {
+    fn response(&mut self, response: Response) -> proto::Result<()> {
+        self.writer.response(response)
+    }
+
+    fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
+        self.writer.batch_response(responses)
+    }
+}
+
+/// Buffers both halves of a stream, so that every message goes out in one write on `flush`.
+pub(crate) struct Buffered<S> {
+    reader: BufReader<S>,
+    out: Vec<u8>,
+}
+
+impl<S: Read + Write> Buffered<S> {
+    pub(crate) fn new(stream: S) -> Buffered<S> {
+        Buffered {
+            reader: BufReader::new(stream),
+            out: Vec::new(),
+        }
+    }
+}
+
+impl<S: Read> Read for Buffered<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<S: Read> BufRead for Buffered<S> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt)
+    }
+}
+
+impl<S: Write> Write for Buffered<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        let result = stream.write_all(&self.out).and_then(|()| stream.flush());
+        self.out.clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::io::{self, BufReader, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+
+    use serde_json::Value;
+
+    use crate::client::Client;
+    use crate::proto::{Id, Response};
+    use crate::proto::spec::{errors, ClientReader, ClientStream, ServerStream};
+    use crate::proto::trans::{GetResponse, SendResponse, ServerResponse};
+    use crate::router::Router;
+
+    use super::{serve_connection, Buffered, Server, TcpServer};
+
+    fn add_router() -> Arc<Router> {
+        let mut router = Router::new();
+        router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+        Arc::new(router)
+    }
+
+    // Serves `n` connections on a thread
+    fn serve_n<S>(mut server: S, router: Arc<Router>, n: usize) -> thread::JoinHandle<S>
+        where S: Server + Send + 'static
+    {
+        thread::spawn(move|| {
+            for _ in 0..n {
+                let (stream, _) = server.accept().unwrap();
+                serve_connection(&mut ServerStream::new(Buffered::new(stream)), &*router).unwrap();
+            }
+            server
+        })
+    }
+
+    #[test]
+    fn test_tcp_server() {
+        let server = TcpServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = serve_n(server, add_router(), 1);
+
+        let stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut client = Client::new(ClientStream::new(Buffered::new(stream)));
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+        drop(client);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_invalid_request() {
+        let server = TcpServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let handle = serve_n(server, add_router(), 1);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":1}\r\n\
+                           {\"jsonrpc\":\"2.0\",,\"id\":2}\r\n\
+                           {\"jsonrpc\":\"1.0\",\"method\":\"add\",\"id\":3}\r\n\
+                           {\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[3,4],\"id\":4}\r\n").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        // Each invalid request is answered with a null id, and the connection stays open
+        let mut reader = ClientReader::new(BufReader::new(stream));
+        let mut next = || match reader.get_response().unwrap() {
+            Some(ServerResponse::Single(resp)) => resp,
+            other => panic!("Expecting a response, but found {:?}", other),
+        };
+        assert_eq!(Response::result(Value::from(3), 1), next());
+        let resp = next();
+        assert_eq!(&Id::Null, resp.id());
+        assert_eq!(errors::ERRCODE_PARSE_ERROR, resp.into_result().unwrap_err().code);
+        let resp = next();
+        assert_eq!(&Id::Null, resp.id());
+        assert_eq!(errors::ERRCODE_INVALID_REQUEST, resp.into_result().unwrap_err().code);
+        assert_eq!(Response::result(Value::from(7), 4), next());
+        assert!(reader.get_response().unwrap().is_none());
+
+        handle.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_server() {
+        use std::os::unix::net::{UnixListener, UnixStream};
+
+        use super::UnixServer;
+
+        let path = env::temp_dir().join(format!("jsonrpc-test-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // A dead server leaves its socket file behind
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let server = UnixServer::bind(&path).unwrap();
+        match UnixServer::bind(&path) {
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse => {},
+            other => panic!("Expecting the socket to be in use, but found {:?}", other.map(|s| s.path().to_owned())),
+        }
+        // The probe of the second bind is a connection too
+        let handle = serve_n(server, add_router(), 2);
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut client = Client::new(ClientStream::new(Buffered::new(stream)));
+        assert_eq!(5, client.call::<_, i64>("add", (2, 3)).unwrap());
+        drop(client);
+
+        drop(handle.join().unwrap());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_buffered_writes() {
+        // Counts the writes reaching the stream
+        struct Counting(Vec<Vec<u8>>);
+
+        impl io::Read for Counting {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Ok(0)
+            }
+        }
+
+        impl Write for Counting {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // A whole response goes out at once, delimiter included
+        let mut stream = ServerStream::new(Buffered::new(Counting(Vec::new())));
+        stream.response(Response::result(Value::from(3), 1)).unwrap();
+        let writes = &stream.get_ref().reader.get_ref().0;
+        assert_eq!(1, writes.len());
+        assert!(writes[0].ends_with(b"}\r\n"));
+    }
+}
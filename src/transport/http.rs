@@ -0,0 +1,654 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! JSON-RPC over HTTP/1.1 POST.
+//!
+//! Only what JSON-RPC needs is supported: bodies must have a `Content-Length`, and chunked
+//! transfer encoding is refused.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+
+use crate::proto::{self, Id, Request, Response};
+use crate::proto::framing::DEFAULT_MAX_FRAME_SIZE;
+use crate::proto::spec::{errors, ClientReader, ServerReader};
+use crate::proto::trans::{GetRequest, GetResponse, SendRequest, ServerResponse};
+use crate::router::Dispatcher;
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// How long `HttpServer::serve` waits on a silent connection before closing it.
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long `HttpClient` waits to connect, and then on each read or write, by default.
+pub const DEFAULT_CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Start line and headers of an HTTP message.
+struct Head {
+    start: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name)
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    }
+
+    fn content_length(&self) -> io::Result<Option<usize>> {
+        match self.header("Content-Length") {
+            Some(len) => {
+                len.parse().map(Some).map_err(|_| {
+                    invalid_data(format!("Invalid Content-Length {:?}", len))
+                })
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+fn invalid_data(detail: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, detail)
+}
+
+/// Reads the head of the next message, returning `None` on EOF before its first byte.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Option<Head>> {
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let n = reader.by_ref().take((MAX_HEAD_SIZE - size) as u64 + 1).read_line(&mut line)?;
+        size += n;
+
+        if n == 0 {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended in the middle of an HTTP head"));
+        }
+        if size > MAX_HEAD_SIZE || !line.ends_with('\n') {
+            return Err(invalid_data(format!("HTTP head exceeds {} bytes", MAX_HEAD_SIZE)));
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_owned();
+        if line.is_empty() {
+            // Tolerate empty lines before the start line, as RFC 7230 asks to
+            if lines.is_empty() {
+                continue;
+            }
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut lines = lines.into_iter();
+    let start = lines.next().unwrap();
+    let headers = lines.map(|line| {
+        match line.find(':') {
+            Some(pos) => Ok((line[..pos].trim().to_owned(), line[pos + 1..].trim().to_owned())),
+            None => Err(invalid_data(format!("Malformed HTTP header {:?}", line))),
+        }
+    }).collect::<io::Result<Vec<_>>>()?;
+
+    Ok(Some(Head { start, headers }))
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn read_body<R: Read>(reader: &mut R, length: usize) -> io::Result<Vec<u8>> {
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// Serves JSON-RPC requests POSTed to any path, with a `Dispatcher`.
+///
+/// A body that cannot be decoded is answered with `400 Bad Request` and the JSON-RPC error, and a
+/// body made only of notifications with `204 No Content`. Connections are kept alive as HTTP/1.1
+/// asks, unless the client wants otherwise.
+///
+/// `serve` handles each connection on a thread of its own, and closes a connection silent for
+/// longer than its read timeout so that idle clients do not pile up. To serve connections in
+/// another way, call `handle_connection` yourself.
+pub struct HttpServer<D> {
+    dispatcher: D,
+    max_body_size: usize,
+    read_timeout: Option<Duration>,
+}
+
+impl<D: Dispatcher> HttpServer<D> {
+    pub fn new(dispatcher: D) -> HttpServer<D> {
+        HttpServer {
+            dispatcher,
+            max_body_size: DEFAULT_MAX_FRAME_SIZE,
+            read_timeout: Some(DEFAULT_READ_TIMEOUT),
+        }
+    }
+
+    pub fn dispatcher(&self) -> &D {
+        &self.dispatcher
+    }
+
+    /// Bodies longer than this are refused with `413 Payload Too Large`.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    /// How long `serve` waits for the next read on a connection, `None` waiting forever.
+    ///
+    /// A connection that times out between two requests is closed quietly, and one that times
+    /// out in the middle of a request is dropped with an error.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// Serves every connection accepted on `listener`, each on a thread of its own.
+    ///
+    /// If accepting fails, this returns the error once the connections already accepted are closed.
+    pub fn serve(&self, listener: &TcpListener) -> io::Result<()>
+        where D: Sync
+    {
+        thread::scope(|scope| {
+            loop {
+                let (stream, peer_addr) = listener.accept()?;
+                debug!("Got HTTP connection from {}", peer_addr);
+
+                if let Err(err) = stream.set_read_timeout(self.read_timeout) {
+                    error!("HTTP connection from {} dropped: {}", peer_addr, err);
+                    continue;
+                }
+
+                scope.spawn(move|| {
+                    if let Err(err) = self.handle_connection(stream) {
+                        error!("HTTP connection from {} failed: {}", peer_addr, err);
+                    }
+                });
+            }
+        })
+    }
+
+    /// Serves the requests of one connection until it is closed.
+    ///
+    /// If reading `stream` times out while waiting for the next request, the connection is closed.
+    pub fn handle_connection<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut reader = BufReader::new(stream);
+        loop {
+            let head = match read_head(&mut reader) {
+                Ok(Some(head)) => head,
+                Ok(None) => return Ok(()),
+                Err(ref err) if is_timeout(err) => {
+                    debug!("Closing idle HTTP connection");
+                    return Ok(());
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::InvalidData => {
+                    return write_response(reader.get_mut(), 400, "Bad Request", &[], None, true);
+                },
+                Err(err) => return Err(err),
+            };
+
+            let mut parts = head.start.split(' ');
+            let (method, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(method), Some(_target), Some(version), None) => (method.to_owned(), version.to_owned()),
+                _ => return write_response(reader.get_mut(), 400, "Bad Request", &[], None, true),
+            };
+
+            let close = match &version[..] {
+                "HTTP/1.1" => head.has_token("Connection", "close"),
+                "HTTP/1.0" => !head.has_token("Connection", "keep-alive"),
+                _ => return write_response(reader.get_mut(), 505, "HTTP Version Not Supported", &[], None, true),
+            };
+
+            if head.header("Transfer-Encoding").is_some() {
+                return write_response(reader.get_mut(), 501, "Not Implemented", &[], None, true);
+            }
+
+            let length = match head.content_length() {
+                Ok(Some(length)) => length,
+                Ok(None) if method != "POST" => 0,
+                Ok(None) => return write_response(reader.get_mut(), 411, "Length Required", &[], None, true),
+                Err(..) => return write_response(reader.get_mut(), 400, "Bad Request", &[], None, true),
+            };
+            if length > self.max_body_size {
+                return write_response(reader.get_mut(), 413, "Payload Too Large", &[], None, true);
+            }
+            let body = read_body(&mut reader, length)?;
+
+            if method != "POST" {
+                write_response(reader.get_mut(), 405, "Method Not Allowed", &[("Allow", "POST")], None, close)?;
+            } else {
+                let (status, reason, body) = self.handle_body(&body)?;
+                write_response(reader.get_mut(), status, reason, &[], body.as_ref().map(|b| &b[..]), close)?;
+            }
+
+            if close {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle_body(&self, body: &[u8]) -> io::Result<(u16, &'static str, Option<Vec<u8>>)> {
+        // The same decoding as on a stream, so errors are reported alike
+        let (status, reason, resp) = match ServerReader::new(body).get_request() {
+            Ok(Some(req)) => {
+                match self.dispatcher.dispatch_request(req) {
+                    Some(resp) => (200, "OK", resp),
+                    None => return Ok((204, "No Content", None)),
+                }
+            },
+            Ok(None) => {
+                let err = errors::InvalidRequest::with_detail("Empty request body".to_owned());
+                (400, "Bad Request", ServerResponse::Single(Response::error(err, Id::Null)))
+            },
+            Err(err) => (400, "Bad Request", ServerResponse::Single(Response::error(err.to_protocol_error(), Id::Null))),
+        };
+
+        let body = serde_json::to_vec(&resp)?;
+        Ok((status, reason, Some(body)))
+    }
+}
+
+fn write_response<W: Write>(writer: &mut W,
+                            status: u16,
+                            reason: &str,
+                            headers: &[(&str, &str)],
+                            body: Option<&[u8]>,
+                            close: bool)
+                            -> io::Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\n", status, reason)?;
+    for (name, value) in headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    if close {
+        writer.write_all(b"Connection: close\r\n")?;
+    }
+
+    match body {
+        Some(body) => {
+            write!(writer, "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n", body.len())?;
+            writer.write_all(body)?;
+        },
+        // 204 must not even have a Content-Length
+        None if status == 204 => writer.write_all(b"\r\n")?,
+        None => writer.write_all(b"Content-Length: 0\r\n\r\n")?,
+    }
+    writer.flush()
+}
+
+/// Client side of JSON-RPC over HTTP, for use with `client::Client`.
+///
+/// Every request or batch is POSTed on its own connection, and the responses found in the reply
+/// are handed out by `get_response`. A reply with another status than 2xx and no JSON-RPC error
+/// in its body fails the request with an I/O error, and so does a body longer than the maximum.
+/// Connecting, and each read or write after that, times out after `DEFAULT_CLIENT_TIMEOUT` unless
+/// configured otherwise.
+pub struct HttpClient {
+    addrs: Vec<SocketAddr>,
+    host: String,
+    path: String,
+    max_body_size: usize,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    responses: VecDeque<ServerResponse>,
+}
+
+impl HttpClient {
+    /// Creates a client POSTing to `path` on the addresses `addr` resolves to, tried in turn.
+    ///
+    /// `addr` is sent as the `Host` header as it is given, such as `"example.com:8080"`.
+    pub fn new<A: ToSocketAddrs + fmt::Display>(addr: A, path: &str) -> io::Result<HttpClient> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"));
+        }
+
+        Ok(HttpClient {
+            addrs,
+            host: addr.to_string(),
+            path: path.to_owned(),
+            max_body_size: DEFAULT_MAX_FRAME_SIZE,
+            connect_timeout: Some(DEFAULT_CLIENT_TIMEOUT),
+            read_timeout: Some(DEFAULT_CLIENT_TIMEOUT),
+            write_timeout: Some(DEFAULT_CLIENT_TIMEOUT),
+            responses: VecDeque::new(),
+        })
+    }
+
+    /// The first address the client connects to.
+    pub fn addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Replies with a longer body fail the request, before the body is read.
+    pub fn set_max_body_size(&mut self, max_body_size: usize) {
+        self.max_body_size = max_body_size;
+    }
+
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// How long connecting to each address may take, `None` leaving it to the system.
+    pub fn set_connect_timeout(&mut self, connect_timeout: Option<Duration>) {
+        self.connect_timeout = connect_timeout;
+    }
+
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// How long each read of a reply may take, `None` waiting forever.
+    pub fn set_read_timeout(&mut self, read_timeout: Option<Duration>) {
+        self.read_timeout = read_timeout;
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    /// How long each write of a request may take, `None` waiting forever.
+    pub fn set_write_timeout(&mut self, write_timeout: Option<Duration>) {
+        self.write_timeout = write_timeout;
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in &self.addrs {
+            let result = match self.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match result {
+                Ok(stream) => {
+                    stream.set_read_timeout(self.read_timeout)?;
+                    stream.set_write_timeout(self.write_timeout)?;
+                    return Ok(stream);
+                },
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.expect("a client has at least one address"))
+    }
+
+    fn post(&mut self, body: &[u8]) -> proto::Result<()> {
+        let mut stream = self.connect()?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\n\
+                        Content-Type: application/json\r\nAccept: application/json\r\n\
+                        Content-Length: {}\r\nConnection: close\r\n\r\n",
+               self.path, self.host, body.len())?;
+        stream.write_all(body)?;
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let head = read_head(&mut reader)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before the HTTP response")
+        })?;
+
+        let status = head.start.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok()).ok_or_else(|| {
+            invalid_data(format!("Malformed HTTP status line {:?}", head.start))
+        })?;
+        if head.header("Transfer-Encoding").is_some() {
+            return Err(invalid_data("Unsupported Transfer-Encoding".to_owned()).into());
+        }
+
+        let too_large = || invalid_data(format!("HTTP response body exceeds {} bytes", self.max_body_size));
+        let body = match head.content_length()? {
+            Some(length) if length > self.max_body_size => return Err(too_large().into()),
+            Some(length) => read_body(&mut reader, length)?,
+            None if status == 204 => Vec::new(),
+            None => {
+                let mut body = Vec::new();
+                reader.take(self.max_body_size as u64 + 1).read_to_end(&mut body)?;
+                if body.len() > self.max_body_size {
+                    return Err(too_large().into());
+                }
+                body
+            }
+        };
+
+        let resp = if body.iter().all(u8::is_ascii_whitespace) {
+            None
+        } else {
+            match ClientReader::new(&body[..]).get_response() {
+                Ok(resp) => resp,
+                Err(err) if (200..300).contains(&status) => return Err(err),
+                Err(..) => None,
+            }
+        };
+
+        match resp {
+            Some(resp) => self.responses.push_back(resp),
+            None if (200..300).contains(&status) => {},
+            None => {
+                let err = io::Error::other(format!("Unexpected HTTP status {}", head.start));
+                return Err(proto::Error::IoError(err));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SendRequest for HttpClient {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        let body = serde_json::to_vec(&request).map_err(proto::Error::EncoderError)?;
+        self.post(&body)
+    }
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        let body = serde_json::to_vec(&requests).map_err(proto::Error::EncoderError)?;
+        self.post(&body)
+    }
+}
+
+impl GetResponse for HttpClient {
+    /// Returns the next response received, or `None` if every reply has been handed out.
+    fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
+        Ok(self.responses.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::Value;
+
+    use crate::Error;
+    use crate::client::Client;
+    use crate::proto::spec::errors;
+    use crate::router::Router;
+
+    use super::{is_timeout, read_body, read_head, HttpClient, HttpServer};
+
+    #[test]
+    fn test_http_loopback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move|| {
+            let mut router = Router::new();
+            router.add_method("add", |(a, b): (i64, i64)| Ok(a + b))
+                  .add_method("touch", |_: Value| Ok(()));
+            let server = HttpServer::new(router);
+
+            for _ in 0..4 {
+                let (stream, _) = listener.accept().unwrap();
+                server.handle_connection(stream).unwrap();
+            }
+        });
+
+        let mut client = Client::new(HttpClient::new(addr, "/rpc").unwrap());
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+        client.notify("touch", ("ping",)).unwrap();
+        match client.call::<_, i64>("sub", (1, 2)) {
+            Err(Error::ProtocolError(err)) => assert_eq!(errors::ERRCODE_METHOD_NOT_FOUND, err.code),
+            other => panic!("Expecting MethodNotFound, but found {:?}", other),
+        }
+
+        // Several requests on one kept alive connection
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /rpc HTTP/1.1\r\nHost: localhost\r\n\r\n\
+                           POST /rpc HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1\r\n\r\n{\
+                           POST /rpc HTTP/1.1\r\nHost: localhost\r\nContent-Length: 36\r\nConnection: close\r\n\r\n\
+                           [{\"jsonrpc\":\"2.0\",\"method\":\"touch\"}]").unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+
+        let mut replies = replies.split("HTTP/1.1 ").skip(1);
+        assert!(replies.next().unwrap().starts_with("405 Method Not Allowed\r\nAllow: POST\r\n"));
+        let parse_error = replies.next().unwrap();
+        assert!(parse_error.starts_with("400 Bad Request\r\n"));
+        assert!(parse_error.contains("\"code\":-32700"));
+        assert!(replies.next().unwrap().starts_with("204 No Content\r\nConnection: close\r\n\r\n"));
+        assert!(replies.next().is_none());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_http_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The server is left running, blocked accepting, once the test is over
+        thread::spawn(move|| {
+            let mut router = Router::new();
+            router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+            let mut server = HttpServer::new(router);
+            server.set_read_timeout(Some(Duration::from_millis(100)));
+            server.serve(&listener).unwrap();
+        });
+
+        // A silent connection is closed, and does not keep the next client waiting forever
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let mut client = Client::new(HttpClient::new(addr, "/rpc").unwrap());
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+        assert_eq!(0, idle.read(&mut [0u8; 16]).unwrap());
+    }
+
+    #[test]
+    fn test_http_serve_threads() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move|| {
+            let mut router = Router::new();
+            router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+            let mut server = HttpServer::new(router);
+            server.set_read_timeout(None);
+            server.serve(&listener).unwrap();
+        });
+
+        // Without any timeout, a silent connection still does not hold the next one back
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut http = HttpClient::new(addr, "/rpc").unwrap();
+        http.set_read_timeout(Some(Duration::from_secs(5)));
+        let mut client = Client::new(http);
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+    }
+
+    #[test]
+    fn test_http_client_max_body_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let head = read_head(&mut reader).unwrap().unwrap();
+            read_body(&mut reader, head.content_length().unwrap().unwrap()).unwrap();
+            reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n").unwrap();
+        });
+
+        let mut http = HttpClient::new(addr, "/rpc").unwrap();
+        http.set_max_body_size(1024);
+        let mut client = Client::new(http);
+        match client.call::<_, i64>("add", (1, 2)) {
+            Err(Error::IoError(err)) => assert_eq!(io::ErrorKind::InvalidData, err.kind()),
+            other => panic!("Expecting an I/O error, but found {:?}", other),
+        }
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_http_client_host_and_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (done, wait) = mpsc::channel::<()>();
+
+        // The request is read but never answered
+        let server = thread::spawn(move|| {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let head = read_head(&mut reader).unwrap().unwrap();
+            let _ = wait.recv();
+            head.header("Host").map(str::to_owned)
+        });
+
+        let mut http = HttpClient::new(format!("localhost:{}", port), "/rpc").unwrap();
+        http.set_read_timeout(Some(Duration::from_millis(100)));
+        let mut client = Client::new(http);
+        match client.call::<_, i64>("add", (1, 2)) {
+            Err(Error::IoError(ref err)) if is_timeout(err) => {},
+            other => panic!("Expecting a timeout, but found {:?}", other),
+        }
+
+        // The Host header is the name given, not the address it resolved to
+        done.send(()).unwrap();
+        assert_eq!(Some(format!("localhost:{}", port)), server.join().unwrap());
+    }
+}
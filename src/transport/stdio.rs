@@ -0,0 +1,232 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! JSON-RPC over the standard streams of a process.
+//!
+//! A parent talks to a child process with `ChildTransport`, and the child serves it with
+//! `serve_stdio`. Stderr is never touched, so it stays free for logs.
+
+use std::io::{self, BufReader, Read, StdinLock, StdoutLock, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+
+use crate::client::connection_closed;
+use crate::proto::{self, Request};
+use crate::proto::framing::{Concatenated, Framing};
+use crate::proto::spec::{ClientStream, ServerStream};
+use crate::proto::trans::{GetResponse, SendRequest, ServerResponse};
+use crate::router::Dispatcher;
+use crate::server::serve_connection;
+
+/// Reads from one stream and writes to another, as a single `Read + Write`.
+pub struct Pipes<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Pipes<R, W> {
+    pub fn new(reader: R, writer: W) -> Pipes<R, W> {
+        Pipes {
+            reader,
+            writer,
+        }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R: Read, W> Read for Pipes<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Pipes<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub type ChildPipes = Pipes<BufReader<ChildStdout>, ChildStdin>;
+
+/// Client transport to a child process, sending requests on its stdin and reading its stdout.
+///
+/// Stderr is left as configured on the `Command`, inherited by default. Once the child closes its
+/// stdout, which usually means it exited, reading fails with an I/O error telling how, so that the
+/// calls still waiting are failed. The child is killed and reaped when the transport is dropped,
+/// unless `close` let it exit first.
+pub struct ChildTransport<F: Framing = Concatenated> {
+    // Only taken by `close`
+    stream: Option<ClientStream<ChildPipes, F>>,
+    child: Child,
+}
+
+impl ChildTransport {
+    pub fn spawn(command: &mut Command) -> io::Result<ChildTransport> {
+        ChildTransport::spawn_with_framing(command, Concatenated)
+    }
+}
+
+impl<F: Framing> ChildTransport<F> {
+    pub fn spawn_with_framing(command: &mut Command, framing: F) -> io::Result<ChildTransport<F>> {
+        let mut child = command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+
+        Ok(ChildTransport {
+            stream: Some(ClientStream::with_framing(Pipes::new(stdout, stdin), framing)),
+            child,
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// The child process, whose stderr can still be taken if it was piped.
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Returns the exit status of the child if it has exited, without waiting.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()
+    }
+
+    /// Closes the stdin of the child, then waits for it to exit.
+    pub fn close(mut self) -> io::Result<ExitStatus> {
+        self.stream.take();
+        self.child.wait()
+    }
+
+    fn stream(&mut self) -> &mut ClientStream<ChildPipes, F> {
+        self.stream.as_mut().expect("the stream is only taken by close")
+    }
+}
+
+impl<F: Framing> Drop for ChildTransport<F> {
+    fn drop(&mut self) {
+        // A child that has exited already, as after `close`, only has to be reaped
+        if let Ok(None) = self.child.try_wait() {
+            if let Err(err) = self.child.kill() {
+                warn!("Failed to kill child process {}: {}", self.child.id(), err);
+            }
+        }
+        if let Err(err) = self.child.wait() {
+            warn!("Failed to reap child process {}: {}", self.child.id(), err);
+        }
+    }
+}
+
+impl<F: Framing> SendRequest for ChildTransport<F> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        self.stream().request(request)
+    }
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        self.stream().batch_request(requests)
+    }
+}
+
+impl<F: Framing> GetResponse for ChildTransport<F> {
+    /// Fails once the child closed its stdout, as no response can come anymore.
+    fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
+        if let Some(resp) = self.stream().get_response()? {
+            return Ok(Some(resp));
+        }
+
+        let reason = match self.child.try_wait() {
+            Ok(Some(status)) => format!("child process {} exited with {}", self.child.id(), status),
+            Ok(None) => format!("child process {} closed its stdout", self.child.id()),
+            Err(err) => format!("child process {} closed its stdout, and could not be checked: {}", self.child.id(), err),
+        };
+        debug!("{}", reason);
+        Err(connection_closed(&reason))
+    }
+}
+
+/// Serves `dispatcher` over the stdin and stdout of the current process, until stdin is closed.
+///
+/// Nothing else may write to stdout meanwhile, so logs must go to stderr.
+pub fn serve_stdio<D, F>(dispatcher: &D, framing: F) -> proto::Result<()>
+    where D: Dispatcher,
+          F: Framing
+{
+    let pipes: Pipes<StdinLock<'static>, StdoutLock<'static>> = Pipes::new(io::stdin().lock(), io::stdout().lock());
+    serve_connection(&mut ServerStream::with_framing(pipes, framing), dispatcher)
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::io::{self, Read};
+    use std::process::{Command, Stdio};
+
+    use crate::Error;
+    use crate::client::Client;
+
+    use super::ChildTransport;
+
+    #[test]
+    fn test_stdio_child() {
+        let mut command = Command::new("sh");
+        command.arg("-c")
+               .arg("read req; printf '{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":3}\\r\\n'; read req; echo done >&2; exit 3")
+               .stderr(Stdio::piped());
+
+        let mut client = Client::new(ChildTransport::spawn(&mut command).unwrap());
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+        // The second request is read, but the child exits without answering it
+        match client.call::<_, i64>("add", (3, 4)) {
+            Err(Error::IoError(ref err)) if err.kind() == io::ErrorKind::ConnectionAborted => {},
+            other => panic!("Expecting the child to be gone, but found {:?}", other),
+        }
+
+        let mut transport = client.into_inner();
+        let mut stderr = String::new();
+        transport.child_mut().stderr.take().unwrap().read_to_string(&mut stderr).unwrap();
+        assert_eq!("done\n", stderr);
+
+        let status = transport.close().unwrap();
+        assert_eq!(Some(3), status.code());
+    }
+
+    #[test]
+    fn test_stdio_child_drop() {
+        let transport = ChildTransport::spawn(Command::new("sleep").arg("60")).unwrap();
+        let pid = transport.id().to_string();
+        drop(transport);
+
+        // Neither running nor left as a zombie
+        let status = Command::new("kill").arg("-0").arg(&pid).stderr(Stdio::null()).status().unwrap();
+        assert!(!status.success());
+    }
+}
@@ -0,0 +1,289 @@
+// The MIT License (MIT)
+
+// Copyright (c) 2015 Y. T. Chung <zonyitoo@gmail.com>
+
+//  Permission is hereby granted, free of charge, to any person obtaining a
+//  copy of this software and associated documentation files (the "Software"),
+//  to deal in the Software without restriction, including without limitation
+//  the rights to use, copy, modify, merge, publish, distribute, sublicense,
+//  and/or sell copies of the Software, and to permit persons to whom the
+//  Software is furnished to do so, subject to the following conditions:
+//
+//  The above copyright notice and this permission notice shall be included in
+//  all copies or substantial portions of the Software.
+//
+//  THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS
+//  OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+//  FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+//  AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+//  LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+//  FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+//  DEALINGS IN THE SOFTWARE.
+
+//! JSON-RPC over WebSocket, one message per text frame.
+//!
+//! Both ends may send requests, so a `WsStream` is at the same time a client and a server
+//! transport. A server pushes notifications or calls the client with `SendRequest`, on the very
+//! socket it reads requests from.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use serde::Serialize;
+use serde_json;
+use tungstenite::{self, WebSocket};
+use tungstenite::handshake::HandshakeError;
+
+use crate::proto::{self, InternalError, InternalErrorKind, Request, Response};
+use crate::proto::spec::message_from_json;
+use crate::proto::trans::{ClientRequest, GetMessage, GetRequest, GetResponse, Message, SendRequest, SendResponse,
+                          ServerResponse};
+
+/// A WebSocket carrying JSON-RPC messages both ways.
+///
+/// Requests read while waiting for a response, and responses read while waiting for a request,
+/// are kept until asked for.
+pub struct WsStream<S> {
+    socket: WebSocket<S>,
+    requests: VecDeque<ClientRequest>,
+    responses: VecDeque<ServerResponse>,
+}
+
+impl<S: Read + Write> WsStream<S> {
+    pub fn new(socket: WebSocket<S>) -> WsStream<S> {
+        WsStream {
+            socket,
+            requests: VecDeque::new(),
+            responses: VecDeque::new(),
+        }
+    }
+
+    /// Performs the client handshake on `stream` for `url`, such as `ws://localhost:8080/rpc`.
+    pub fn client(url: &str, stream: S) -> proto::Result<WsStream<S>> {
+        match tungstenite::client(url, stream) {
+            Ok((socket, _)) => Ok(WsStream::new(socket)),
+            Err(HandshakeError::Failure(err)) => Err(from_ws_error(err)),
+            Err(HandshakeError::Interrupted(..)) => Err(would_block()),
+        }
+    }
+
+    /// Performs the server handshake on an accepted `stream`.
+    pub fn accept(stream: S) -> proto::Result<WsStream<S>> {
+        match tungstenite::accept(stream) {
+            Ok(socket) => Ok(WsStream::new(socket)),
+            Err(HandshakeError::Failure(err)) => Err(from_ws_error(err)),
+            Err(HandshakeError::Interrupted(..)) => Err(would_block()),
+        }
+    }
+
+    pub fn get_ref(&self) -> &WebSocket<S> {
+        &self.socket
+    }
+
+    pub fn get_mut(&mut self) -> &mut WebSocket<S> {
+        &mut self.socket
+    }
+
+    pub fn into_inner(self) -> WebSocket<S> {
+        self.socket
+    }
+
+    /// Reads the next message, whatever its kind, returning `None` once the socket is closed.
+    ///
+    /// Messages kept by `get_request` or `get_response` come first.
+    pub fn read_message(&mut self) -> proto::Result<Option<Message>> {
+        if let Some(req) = self.requests.pop_front() {
+            return Ok(Some(Message::Request(req)));
+        }
+        if let Some(resp) = self.responses.pop_front() {
+            return Ok(Some(Message::Response(resp)));
+        }
+        self.read_frame()
+    }
+
+    /// Sends any message, such as a response to a request of the server.
+    pub fn send_message(&mut self, message: &Message) -> proto::Result<()> {
+        self.send(message)
+    }
+
+    /// Closes the socket, sending a Close frame to the peer.
+    pub fn close(&mut self) -> proto::Result<()> {
+        match self.socket.close(None) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => Ok(()),
+            Err(err) => Err(from_ws_error(err)),
+        }
+    }
+
+    fn read_frame(&mut self) -> proto::Result<Option<Message>> {
+        loop {
+            match self.socket.read() {
+                Ok(tungstenite::Message::Text(text)) => {
+                    let msg = serde_json::from_str(&text).map_err(proto::Error::ParserError)?;
+                    return message_from_json(msg).map(Some);
+                },
+                Ok(tungstenite::Message::Binary(..)) => {
+                    let ierr = InternalError::new(InternalErrorKind::InvalidFrame,
+                                                  "Invalid frame",
+                                                  Some("Expecting a text frame, but found a binary one".to_owned()));
+                    return Err(proto::Error::InternalError(ierr));
+                },
+                Ok(tungstenite::Message::Close(..)) => {
+                    // Sends back the Close frame queued by tungstenite, completing the close handshake
+                    match self.socket.flush() {
+                        Ok(()) | Err(tungstenite::Error::ConnectionClosed) => return Ok(None),
+                        Err(err) => return Err(from_ws_error(err)),
+                    }
+                },
+                // Pings are answered by tungstenite itself
+                Ok(..) => {},
+                Err(tungstenite::Error::ConnectionClosed) | Err(tungstenite::Error::AlreadyClosed) => return Ok(None),
+                Err(err) => return Err(from_ws_error(err)),
+            }
+        }
+    }
+
+    fn send<T: Serialize>(&mut self, message: &T) -> proto::Result<()> {
+        let text = serde_json::to_string(message).map_err(proto::Error::EncoderError)?;
+        self.socket.send(tungstenite::Message::Text(text)).map_err(from_ws_error)
+    }
+}
+
+impl<S: Read + Write> SendRequest for WsStream<S> {
+    fn request(&mut self, request: Request) -> proto::Result<()> {
+        self.send(&request)
+    }
+
+    fn batch_request(&mut self, requests: Vec<Request>) -> proto::Result<()> {
+        self.send(&requests)
+    }
+}
+
+impl<S: Read + Write> GetResponse for WsStream<S> {
+    fn get_response(&mut self) -> proto::Result<Option<ServerResponse>> {
+        if let Some(resp) = self.responses.pop_front() {
+            return Ok(Some(resp));
+        }
+
+        loop {
+            match self.read_frame()? {
+                Some(Message::Response(resp)) => return Ok(Some(resp)),
+                Some(Message::Request(req)) => self.requests.push_back(req),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> SendResponse for WsStream<S> {
+    fn response(&mut self, response: Response) -> proto::Result<()> {
+        self.send(&response)
+    }
+
+    fn batch_response(&mut self, responses: Vec<Response>) -> proto::Result<()> {
+        if responses.is_empty() {
+            return Ok(());
+        }
+        self.send(&responses)
+    }
+}
+
+impl<S: Read + Write> GetRequest for WsStream<S> {
+    fn get_request(&mut self) -> proto::Result<Option<ClientRequest>> {
+        if let Some(req) = self.requests.pop_front() {
+            return Ok(Some(req));
+        }
+
+        loop {
+            match self.read_frame()? {
+                Some(Message::Request(req)) => return Ok(Some(req)),
+                Some(Message::Response(resp)) => self.responses.push_back(resp),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> GetMessage for WsStream<S> {
+    fn get_message(&mut self) -> proto::Result<Option<Message>> {
+        self.read_message()
+    }
+}
+
+fn would_block() -> proto::Error {
+    proto::Error::IoError(io::Error::new(io::ErrorKind::WouldBlock, "WebSocket handshake interrupted"))
+}
+
+fn from_ws_error(err: tungstenite::Error) -> proto::Error {
+    match err {
+        tungstenite::Error::Io(err) => proto::Error::IoError(err),
+        err => proto::Error::IoError(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use serde_json::Value;
+
+    use crate::client::Client;
+    use crate::proto::{Id, Request, Response};
+    use crate::proto::trans::{ClientRequest, GetRequest, GetResponse, SendRequest, SendResponse, ServerResponse};
+    use crate::router::{Dispatcher, Router};
+
+    use super::WsStream;
+
+    #[test]
+    fn test_ws_server_push() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move|| {
+            let mut router = Router::new();
+            router.add_method("add", |(a, b): (i64, i64)| Ok(a + b));
+
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = WsStream::accept(stream).unwrap();
+
+            let req = ws.get_request().unwrap().unwrap();
+            let resp = router.dispatch_request(req).unwrap();
+            ws.server_response(resp).unwrap();
+
+            // Pushed on the same socket, once the client is known to be listening
+            ws.request(Request::new_notify("progress".to_owned(), Some(Value::from(50)))).unwrap();
+            ws.request(Request::new("confirm".to_owned(), None, Some("srv-1"))).unwrap();
+
+            let resp = ws.get_response().unwrap();
+            ws.close().unwrap();
+            assert!(ws.get_request().unwrap().is_none());
+            resp
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut client = Client::new(WsStream::client(&format!("ws://{}/rpc", addr), stream).unwrap());
+        assert_eq!(3, client.call::<_, i64>("add", (1, 2)).unwrap());
+
+        let ws = client.get_mut();
+        match ws.get_request().unwrap() {
+            Some(ClientRequest::Single(req)) => {
+                assert_eq!("progress", req.method);
+                assert_eq!(None, req.id);
+            },
+            other => panic!("Expecting a notification, but found {:?}", other),
+        }
+        match ws.get_request().unwrap() {
+            Some(ClientRequest::Single(req)) => {
+                assert_eq!("confirm", req.method);
+                ws.response(Response::result(Value::Bool(true), req.id.unwrap())).unwrap();
+            },
+            other => panic!("Expecting a request, but found {:?}", other),
+        }
+        assert!(ws.get_request().unwrap().is_none());
+
+        match server.join().unwrap() {
+            Some(ServerResponse::Single(resp)) => assert_eq!(Response::result(Value::Bool(true), Id::from("srv-1")), resp),
+            other => panic!("Expecting the response of the client, but found {:?}", other),
+        }
+    }
+}